@@ -0,0 +1,805 @@
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Color {
+    White,
+    Black,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    Pawn,
+    Knight,
+    Bishop,
+    Rook,
+    Queen,
+    King,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Piece {
+    pub color: Color,
+    pub role: Role,
+}
+
+const WHITE_KINGSIDE: u8  = 1;
+const WHITE_QUEENSIDE: u8 = 2;
+const BLACK_KINGSIDE: u8  = 4;
+const BLACK_QUEENSIDE: u8 = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ChessMove {
+    pub from: usize,
+    pub to: usize,
+    pub promotion: Option<Role>,
+}
+
+const KNIGHT_STEPS: [(isize, isize); 8] = [(1, 2), (2, 1), (2, -1), (1, -2), (-1, -2), (-2, -1), (-2, 1), (-1, 2)];
+const KING_STEPS: [(isize, isize); 8]   = [(1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1), (0, -1), (1, -1)];
+const BISHOP_DIRS: [(isize, isize); 4]  = [(1, 1), (1, -1), (-1, 1), (-1, -1)];
+const ROOK_DIRS: [(isize, isize); 4]    = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+const PROMOTIONS: [Role; 4]             = [Role::Queen, Role::Rook, Role::Bishop, Role::Knight];
+
+#[derive(Clone)]
+pub struct Board {
+    squares: [Option<Piece>; 64],
+    turn: Color,
+    castling: u8,
+    ep_square: Option<usize>,
+    halfmoves: u32,
+    fullmoves: u32,
+}
+
+impl Color {
+    pub fn other(self) -> Color {
+        match self {
+            Color::White => Color::Black,
+            Color::Black => Color::White,
+        }
+    }
+}
+
+impl Role {
+    fn from_char(c: char) -> Option<Role> {
+        match c.to_ascii_lowercase() {
+            'p' => Some(Role::Pawn),
+            'n' => Some(Role::Knight),
+            'b' => Some(Role::Bishop),
+            'r' => Some(Role::Rook),
+            'q' => Some(Role::Queen),
+            'k' => Some(Role::King),
+            _ => None
+        }
+    }
+
+    fn to_char(self) -> char {
+        match self {
+            Role::Pawn => 'p',
+            Role::Knight => 'n',
+            Role::Bishop => 'b',
+            Role::Rook => 'r',
+            Role::Queen => 'q',
+            Role::King => 'k',
+        }
+    }
+}
+
+impl Piece {
+    fn from_char(c: char) -> Option<Piece> {
+        let color = if c.is_ascii_uppercase() { Color::White } else { Color::Black };
+        Role::from_char(c).map(|role| Piece { color, role })
+    }
+
+    fn to_char(self) -> char {
+        match self.color {
+            Color::White => self.role.to_char().to_ascii_uppercase(),
+            Color::Black => self.role.to_char(),
+        }
+    }
+}
+
+impl ChessMove {
+    pub fn from_uci(uci: &str) -> Result<ChessMove, String> {
+        if uci.len() < 4 || uci.len() > 5 || !uci.is_ascii() {
+            return Err(format!("Invalid UCI move: {}", uci))
+        }
+        let (from, to) = match (parse_square(&uci[0..2]), parse_square(&uci[2..4])) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return Err(format!("Invalid UCI move: {}", uci))
+        };
+        let promotion = match uci[4..].chars().next() {
+            Some(c) => match Role::from_char(c) {
+                Some(Role::Pawn) | Some(Role::King) | None => return Err(format!("Invalid UCI promotion: {}", uci)),
+                role => role
+            },
+            None => None
+        };
+
+        Ok(ChessMove { from, to, promotion })
+    }
+
+    pub fn uci(&self) -> String {
+        let mut uci = format!("{}{}", square_name(self.from), square_name(self.to));
+        if let Some(role) = self.promotion {
+            uci.push(role.to_char());
+        }
+        uci
+    }
+}
+
+pub fn square_file(square: usize) -> usize {
+    square % 8
+}
+
+pub fn square_rank(square: usize) -> usize {
+    square / 8
+}
+
+pub fn parse_square(s: &str) -> Option<usize> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(file @ 'a'..='h'), Some(rank @ '1'..='8'), None) => Some((rank as usize - '1' as usize)*8 + (file as usize - 'a' as usize)),
+        _ => None
+    }
+}
+
+pub fn square_name(square: usize) -> String {
+    format!("{}{}", (b'a' + square_file(square) as u8) as char, (b'1' + square_rank(square) as u8) as char)
+}
+
+fn offset(square: usize, file_step: isize, rank_step: isize) -> Option<usize> {
+    let file = square_file(square) as isize + file_step;
+    let rank = square_rank(square) as isize + rank_step;
+    if (0..8).contains(&file) && (0..8).contains(&rank) {
+        Some((rank*8 + file) as usize)
+    } else {
+        None
+    }
+}
+
+impl Board {
+    pub fn startpos() -> Board {
+        Board::from_fen(crate::STARTPOS).unwrap()
+    }
+
+    pub fn from_fen(fen: &str) -> Result<Board, String> {
+        let mut fields = fen.split_ascii_whitespace();
+
+        let mut squares = [None; 64];
+        let placement = match fields.next() {
+            Some(placement) => placement,
+            None => return Err("FEN is empty".to_string())
+        };
+        let ranks: Vec<&str> = placement.split('/').collect();
+        if ranks.len() != 8 {
+            return Err(format!("FEN placement must have 8 ranks: {}", placement))
+        }
+        for (i, rank_str) in ranks.iter().enumerate() {
+            let rank = 7 - i;
+            let mut file = 0;
+            for c in rank_str.chars() {
+                if let Some(skip) = c.to_digit(10) {
+                    file += skip as usize;
+                } else {
+                    let piece = match Piece::from_char(c) {
+                        Some(piece) => piece,
+                        None => return Err(format!("Invalid FEN piece: {}", c))
+                    };
+                    if file >= 8 {
+                        return Err(format!("FEN rank too long: {}", rank_str))
+                    }
+                    squares[rank*8 + file] = Some(piece);
+                    file += 1;
+                }
+            }
+            if file != 8 {
+                return Err(format!("FEN rank has wrong length: {}", rank_str))
+            }
+        }
+
+        let turn = match fields.next() {
+            Some("w") | None => Color::White,
+            Some("b") => Color::Black,
+            Some(x) => return Err(format!("Invalid FEN side to move: {}", x))
+        };
+
+        let mut castling = 0;
+        match fields.next() {
+            Some("-") | None => (),
+            Some(x) => for c in x.chars() {
+                castling |= match c {
+                    'K' => WHITE_KINGSIDE,
+                    'Q' => WHITE_QUEENSIDE,
+                    'k' => BLACK_KINGSIDE,
+                    'q' => BLACK_QUEENSIDE,
+                    _ => return Err(format!("Invalid FEN castling rights: {}", x))
+                }
+            }
+        }
+
+        let ep_square = match fields.next() {
+            Some("-") | None => None,
+            Some(x) => match parse_square(x) {
+                Some(square) => Some(square),
+                None => return Err(format!("Invalid FEN en passant square: {}", x))
+            }
+        };
+
+        let halfmoves = match fields.next() {
+            Some(x) => match x.parse() {
+                Ok(n) => n,
+                Err(_) => return Err(format!("Invalid FEN halfmove clock: {}", x))
+            },
+            None => 0
+        };
+
+        let fullmoves = match fields.next() {
+            Some(x) => match x.parse() {
+                Ok(n) => n,
+                Err(_) => return Err(format!("Invalid FEN fullmove number: {}", x))
+            },
+            None => 1
+        };
+
+        let mut board = Board { squares, turn, castling, ep_square, halfmoves, fullmoves };
+        board.fix_castling();
+        Ok(board)
+    }
+
+    pub fn turn(&self) -> Color {
+        self.turn
+    }
+
+    // Half-moves since the start of the game, as implied by the move number.
+    pub fn ply(&self) -> u32 {
+        self.fullmoves.saturating_sub(1)*2 + if self.turn == Color::Black { 1 } else { 0 }
+    }
+
+    pub fn piece_at(&self, square: usize) -> Option<Piece> {
+        self.squares[square]
+    }
+
+    pub fn has_castling(&self, color: Color, kingside: bool) -> bool {
+        let right = match (color, kingside) {
+            (Color::White, true) => WHITE_KINGSIDE,
+            (Color::White, false) => WHITE_QUEENSIDE,
+            (Color::Black, true) => BLACK_KINGSIDE,
+            (Color::Black, false) => BLACK_QUEENSIDE,
+        };
+        self.castling & right != 0
+    }
+
+    pub fn fen(&self) -> String {
+        let mut placement = String::new();
+        for rank in (0..8).rev() {
+            let mut empty = 0;
+            for file in 0..8 {
+                match self.squares[rank*8 + file] {
+                    Some(piece) => {
+                        if empty > 0 {
+                            placement.push_str(&empty.to_string());
+                            empty = 0;
+                        }
+                        placement.push(piece.to_char());
+                    }
+                    None => empty += 1
+                }
+            }
+            if empty > 0 {
+                placement.push_str(&empty.to_string());
+            }
+            if rank > 0 {
+                placement.push('/');
+            }
+        }
+
+        let mut castling = String::new();
+        if self.castling & WHITE_KINGSIDE != 0 { castling.push('K'); }
+        if self.castling & WHITE_QUEENSIDE != 0 { castling.push('Q'); }
+        if self.castling & BLACK_KINGSIDE != 0 { castling.push('k'); }
+        if self.castling & BLACK_QUEENSIDE != 0 { castling.push('q'); }
+        if castling.is_empty() {
+            castling.push('-');
+        }
+
+        let ep = match self.capturable_ep_square() {
+            Some(square) => square_name(square),
+            None => "-".to_string()
+        };
+
+        format!("{} {} {} {} {} {}", placement, if self.turn == Color::White { "w" } else { "b" }, castling, ep, self.halfmoves, self.fullmoves)
+    }
+
+    // The en passant square is only reported when a pawn can actually capture
+    // there, the same way lichess and Polyglot normalize positions.
+    pub fn capturable_ep_square(&self) -> Option<usize> {
+        let square = self.ep_square?;
+        let pawn_rank = if self.turn == Color::White { 4 } else { 3 };
+        let file = square_file(square);
+        let capturer = Some(Piece { color: self.turn, role: Role::Pawn });
+        if (file > 0 && self.squares[pawn_rank*8 + file - 1] == capturer) || (file < 7 && self.squares[pawn_rank*8 + file + 1] == capturer) {
+            Some(square)
+        } else {
+            None
+        }
+    }
+
+    pub fn play_uci(&mut self, uci: &str) -> Result<(), String> {
+        self.play(ChessMove::from_uci(uci)?)
+    }
+
+    pub fn play(&mut self, chess_move: ChessMove) -> Result<(), String> {
+        let ChessMove { from, to, promotion } = chess_move;
+        let piece = match self.squares[from] {
+            Some(piece) if piece.color == self.turn => piece,
+            _ => return Err(format!("No piece to move for {} in {}", chess_move.uci(), self.fen()))
+        };
+        let captured = self.squares[to];
+        let back_rank = if self.turn == Color::White { 0 } else { 7 };
+
+        self.halfmoves += 1;
+        let mut ep_square = None;
+
+        let castling_rook = match (piece.role, captured) {
+            (Role::King, Some(Piece { color, role: Role::Rook })) if color == self.turn => Some(to),
+            (Role::King, _) if square_rank(from) == back_rank && square_rank(to) == back_rank && (from as isize - to as isize).abs() == 2 => {
+                Some(if to > from { back_rank*8 + 7 } else { back_rank*8 })
+            }
+            _ => None
+        };
+
+        match castling_rook {
+            Some(rook_from) => {
+                let (king_to, rook_to) = if rook_from > from {
+                    (back_rank*8 + 6, back_rank*8 + 5)
+                } else {
+                    (back_rank*8 + 2, back_rank*8 + 3)
+                };
+                let rook = self.squares[rook_from].take();
+                self.squares[from] = None;
+                self.squares[king_to] = Some(piece);
+                self.squares[rook_to] = rook;
+            }
+            None => {
+                if piece.role == Role::Pawn {
+                    self.halfmoves = 0;
+                    if Some(to) == self.ep_square && captured.is_none() && square_file(from) != square_file(to) {
+                        self.squares[square_rank(from)*8 + square_file(to)] = None;
+                    }
+                    if (from as isize - to as isize).abs() == 16 {
+                        ep_square = Some((from + to)/2);
+                    }
+                }
+                if captured.is_some() {
+                    self.halfmoves = 0;
+                }
+                self.squares[from] = None;
+                self.squares[to] = Some(match promotion {
+                    Some(role) if piece.role == Role::Pawn => Piece { color: piece.color, role },
+                    _ => piece
+                });
+            }
+        }
+
+        self.ep_square = ep_square;
+        if self.turn == Color::Black {
+            self.fullmoves += 1;
+        }
+        self.turn = self.turn.other();
+        self.fix_castling();
+        Ok(())
+    }
+
+    pub fn is_attacked(&self, square: usize, by: Color) -> bool {
+        let pawn_step = if by == Color::White { -1 } else { 1 };
+        for file_step in &[-1, 1] {
+            if let Some(from) = offset(square, *file_step, pawn_step) {
+                if self.squares[from] == Some(Piece { color: by, role: Role::Pawn }) {
+                    return true
+                }
+            }
+        }
+
+        for (steps, role) in &[(KNIGHT_STEPS, Role::Knight), (KING_STEPS, Role::King)] {
+            for (file_step, rank_step) in steps.iter() {
+                if let Some(from) = offset(square, *file_step, *rank_step) {
+                    if self.squares[from] == Some(Piece { color: by, role: *role }) {
+                        return true
+                    }
+                }
+            }
+        }
+
+        for (dirs, role) in &[(BISHOP_DIRS, Role::Bishop), (ROOK_DIRS, Role::Rook)] {
+            for (file_step, rank_step) in dirs.iter() {
+                let mut current = square;
+                while let Some(next) = offset(current, *file_step, *rank_step) {
+                    match self.squares[next] {
+                        Some(piece) => {
+                            if piece.color == by && (piece.role == *role || piece.role == Role::Queen) {
+                                return true
+                            }
+                            break
+                        }
+                        None => current = next
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
+    fn king_square(&self, color: Color) -> Option<usize> {
+        self.squares.iter().position(|x| *x == Some(Piece { color, role: Role::King }))
+    }
+
+    pub fn in_check(&self) -> bool {
+        match self.king_square(self.turn) {
+            Some(square) => self.is_attacked(square, self.turn.other()),
+            None => false
+        }
+    }
+
+    pub fn legal_moves(&self) -> Vec<ChessMove> {
+        self.pseudo_legal_moves().into_iter()
+            .filter(|x| {
+                let mut board = self.clone();
+                board.play(*x).is_ok() && match board.king_square(self.turn) {
+                    Some(square) => !board.is_attacked(square, board.turn),
+                    None => true
+                }
+            })
+            .collect()
+    }
+
+    fn pseudo_legal_moves(&self) -> Vec<ChessMove> {
+        let mut moves = vec![];
+        let us = self.turn;
+        for from in 0..64 {
+            let piece = match self.squares[from] {
+                Some(piece) if piece.color == us => piece,
+                _ => continue
+            };
+            match piece.role {
+                Role::Pawn => {
+                    let (step, start_rank, last_rank) = if us == Color::White { (1, 1, 7) } else { (-1, 6, 0) };
+                    let mut targets = vec![];
+                    if let Some(to) = offset(from, 0, step) {
+                        if self.squares[to].is_none() {
+                            targets.push(to);
+                            if square_rank(from) == start_rank {
+                                if let Some(to) = offset(to, 0, step) {
+                                    if self.squares[to].is_none() {
+                                        targets.push(to);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    for file_step in &[-1, 1] {
+                        if let Some(to) = offset(from, *file_step, step) {
+                            match self.squares[to] {
+                                Some(target) if target.color != us => targets.push(to),
+                                None if Some(to) == self.ep_square => targets.push(to),
+                                _ => ()
+                            }
+                        }
+                    }
+                    for to in targets {
+                        if square_rank(to) == last_rank {
+                            for role in PROMOTIONS.iter() {
+                                moves.push(ChessMove { from, to, promotion: Some(*role) });
+                            }
+                        } else {
+                            moves.push(ChessMove { from, to, promotion: None });
+                        }
+                    }
+                }
+                Role::Knight | Role::King => {
+                    let steps = if piece.role == Role::Knight { KNIGHT_STEPS } else { KING_STEPS };
+                    for (file_step, rank_step) in steps.iter() {
+                        if let Some(to) = offset(from, *file_step, *rank_step) {
+                            match self.squares[to] {
+                                Some(target) if target.color == us => (),
+                                _ => moves.push(ChessMove { from, to, promotion: None })
+                            }
+                        }
+                    }
+                }
+                Role::Bishop | Role::Rook | Role::Queen => {
+                    let dirs: Vec<&(isize, isize)> = match piece.role {
+                        Role::Bishop => BISHOP_DIRS.iter().collect(),
+                        Role::Rook => ROOK_DIRS.iter().collect(),
+                        _ => BISHOP_DIRS.iter().chain(ROOK_DIRS.iter()).collect()
+                    };
+                    for (file_step, rank_step) in dirs {
+                        let mut current = from;
+                        while let Some(to) = offset(current, *file_step, *rank_step) {
+                            match self.squares[to] {
+                                Some(target) => {
+                                    if target.color != us {
+                                        moves.push(ChessMove { from, to, promotion: None });
+                                    }
+                                    break
+                                }
+                                None => {
+                                    moves.push(ChessMove { from, to, promotion: None });
+                                    current = to;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let (back_rank, kingside, queenside) = if us == Color::White {
+            (0, WHITE_KINGSIDE, WHITE_QUEENSIDE)
+        } else {
+            (56, BLACK_KINGSIDE, BLACK_QUEENSIDE)
+        };
+        let them = us.other();
+        let king = back_rank + 4;
+        if self.castling & (kingside | queenside) != 0 && !self.is_attacked(king, them) {
+            if self.castling & kingside != 0
+                && self.squares[king + 1].is_none() && self.squares[king + 2].is_none()
+                && !self.is_attacked(king + 1, them) && !self.is_attacked(king + 2, them) {
+                moves.push(ChessMove { from: king, to: king + 2, promotion: None });
+            }
+            if self.castling & queenside != 0
+                && self.squares[king - 1].is_none() && self.squares[king - 2].is_none() && self.squares[king - 3].is_none()
+                && !self.is_attacked(king - 1, them) && !self.is_attacked(king - 2, them) {
+                moves.push(ChessMove { from: king, to: king - 2, promotion: None });
+            }
+        }
+
+        moves
+    }
+
+    pub fn san(&self, chess_move: ChessMove) -> Result<String, String> {
+        let legal_moves = self.legal_moves();
+        if !legal_moves.contains(&chess_move) {
+            return Err(format!("Illegal move {} in {}", chess_move.uci(), self.fen()))
+        }
+        let piece = self.squares[chess_move.from].unwrap();
+
+        let mut san = if piece.role == Role::King && (chess_move.from as isize - chess_move.to as isize).abs() == 2 {
+            if chess_move.to > chess_move.from { "O-O".to_string() } else { "O-O-O".to_string() }
+        } else {
+            let capture = self.squares[chess_move.to].is_some() || (piece.role == Role::Pawn && square_file(chess_move.from) != square_file(chess_move.to));
+            let mut san = String::new();
+            if piece.role == Role::Pawn {
+                if capture {
+                    san.push((b'a' + square_file(chess_move.from) as u8) as char);
+                }
+            } else {
+                san.push(piece.role.to_char().to_ascii_uppercase());
+                let others: Vec<&ChessMove> = legal_moves.iter()
+                    .filter(|x| x.to == chess_move.to && x.from != chess_move.from && self.squares[x.from] == Some(piece))
+                    .collect();
+                if !others.is_empty() {
+                    let from_name = square_name(chess_move.from);
+                    if others.iter().all(|x| square_file(x.from) != square_file(chess_move.from)) {
+                        san.push_str(&from_name[0..1]);
+                    } else if others.iter().all(|x| square_rank(x.from) != square_rank(chess_move.from)) {
+                        san.push_str(&from_name[1..2]);
+                    } else {
+                        san.push_str(&from_name);
+                    }
+                }
+            }
+            if capture {
+                san.push('x');
+            }
+            san.push_str(&square_name(chess_move.to));
+            if let Some(role) = chess_move.promotion {
+                san.push('=');
+                san.push(role.to_char().to_ascii_uppercase());
+            }
+            san
+        };
+
+        let mut board = self.clone();
+        board.play(chess_move)?;
+        if board.in_check() {
+            san.push(if board.legal_moves().is_empty() { '#' } else { '+' });
+        }
+        Ok(san)
+    }
+
+    pub fn parse_san(&self, san: &str) -> Result<ChessMove, String> {
+        let text = san.trim_end_matches(&['+', '#', '!', '?'][..]);
+        let legal_moves = self.legal_moves();
+
+        let castle = match text {
+            "O-O" | "0-0" => Some(2),
+            "O-O-O" | "0-0-0" => Some(-2),
+            _ => None
+        };
+        if let Some(step) = castle {
+            return match self.king_square(self.turn) {
+                Some(king) => match legal_moves.iter().find(|x| x.from == king && x.to as isize == king as isize + step) {
+                    Some(chess_move) => Ok(*chess_move),
+                    None => Err(format!("Illegal castling {} in {}", san, self.fen()))
+                },
+                None => Err(format!("No king to castle {} in {}", san, self.fen()))
+            }
+        }
+
+        let mut chars: Vec<char> = text.chars().filter(|c| *c != 'x' && *c != '-' && *c != ':').collect();
+        let promotion = match chars.last() {
+            Some(c) if "NBRQ".contains(*c) && chars.len() > 2 && chars[chars.len() - 2].is_ascii_digit() => {
+                let role = Role::from_char(*c);
+                chars.pop();
+                role
+            }
+            _ => match chars.iter().position(|c| *c == '=') {
+                Some(i) => {
+                    let role = chars.get(i + 1).and_then(|c| Role::from_char(*c));
+                    chars.truncate(i);
+                    role
+                }
+                None => None
+            }
+        };
+        let role = match chars.first() {
+            Some(c) if "NBRQK".contains(*c) => {
+                let role = Role::from_char(*c).unwrap();
+                chars.remove(0);
+                role
+            }
+            _ => Role::Pawn
+        };
+        if chars.len() < 2 {
+            return Err(format!("Invalid SAN move: {}", san))
+        }
+        let to = match parse_square(chars[chars.len() - 2..].iter().collect::<String>().as_str()) {
+            Some(to) => to,
+            None => return Err(format!("Invalid SAN move: {}", san))
+        };
+        let mut from_file = None;
+        let mut from_rank = None;
+        for c in &chars[..chars.len() - 2] {
+            match c {
+                'a'..='h' => from_file = Some(*c as usize - 'a' as usize),
+                '1'..='8' => from_rank = Some(*c as usize - '1' as usize),
+                _ => return Err(format!("Invalid SAN move: {}", san))
+            }
+        }
+
+        let mut candidates = legal_moves.iter().filter(|x| {
+            x.to == to
+                && x.promotion == promotion
+                && self.squares[x.from].map(|piece| piece.role) == Some(role)
+                && from_file.is_none_or(|file| square_file(x.from) == file)
+                && from_rank.is_none_or(|rank| square_rank(x.from) == rank)
+        });
+        match (candidates.next(), candidates.next()) {
+            (Some(chess_move), None) => Ok(*chess_move),
+            (Some(_), Some(_)) => Err(format!("Ambiguous SAN move {} in {}", san, self.fen())),
+            (None, _) => Err(format!("Illegal SAN move {} in {}", san, self.fen()))
+        }
+    }
+
+    fn fix_castling(&mut self) {
+        let white_king = Some(Piece { color: Color::White, role: Role::King });
+        let black_king = Some(Piece { color: Color::Black, role: Role::King });
+        let white_rook = Some(Piece { color: Color::White, role: Role::Rook });
+        let black_rook = Some(Piece { color: Color::Black, role: Role::Rook });
+        if self.squares[4] != white_king || self.squares[7] != white_rook {
+            self.castling &= !WHITE_KINGSIDE;
+        }
+        if self.squares[4] != white_king || self.squares[0] != white_rook {
+            self.castling &= !WHITE_QUEENSIDE;
+        }
+        if self.squares[60] != black_king || self.squares[63] != black_rook {
+            self.castling &= !BLACK_KINGSIDE;
+        }
+        if self.squares[60] != black_king || self.squares[56] != black_rook {
+            self.castling &= !BLACK_QUEENSIDE;
+        }
+    }
+}
+
+impl fmt::Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.fen())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KIWIPETE: &str = "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1";
+
+    fn perft(board: &Board, depth: u32) -> u64 {
+        if depth == 0 {
+            return 1
+        }
+        let moves = board.legal_moves();
+        if depth == 1 {
+            return moves.len() as u64
+        }
+        moves.into_iter().map(|x| {
+            let mut next = board.clone();
+            next.play(x).unwrap();
+            perft(&next, depth - 1)
+        }).sum()
+    }
+
+    #[test]
+    fn perft_startpos() {
+        let board = Board::startpos();
+        assert_eq!(perft(&board, 1), 20);
+        assert_eq!(perft(&board, 2), 400);
+        assert_eq!(perft(&board, 3), 8902);
+        assert_eq!(perft(&board, 4), 197281);
+    }
+
+    #[test]
+    fn perft_kiwipete() {
+        let board = Board::from_fen(KIWIPETE).unwrap();
+        assert_eq!(perft(&board, 1), 48);
+        assert_eq!(perft(&board, 2), 2039);
+        assert_eq!(perft(&board, 3), 97862);
+    }
+
+    #[test]
+    fn perft_endgame() {
+        let board = Board::from_fen("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1").unwrap();
+        assert_eq!(perft(&board, 1), 14);
+        assert_eq!(perft(&board, 2), 191);
+        assert_eq!(perft(&board, 3), 2812);
+        assert_eq!(perft(&board, 4), 43238);
+    }
+
+    #[test]
+    fn perft_promotions() {
+        let board = Board::from_fen("r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1").unwrap();
+        assert_eq!(perft(&board, 1), 6);
+        assert_eq!(perft(&board, 2), 264);
+        assert_eq!(perft(&board, 3), 9467);
+    }
+
+    #[test]
+    fn fen_round_trip() {
+        let fens = [
+            crate::STARTPOS,
+            KIWIPETE,
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3",
+            "r3k2r/8/8/8/8/8/8/R3K2R b Kq - 12 40",
+        ];
+        for fen in fens.iter() {
+            assert_eq!(Board::from_fen(fen).unwrap().fen(), *fen);
+        }
+    }
+
+    #[test]
+    fn fen_after_moves() {
+        let mut board = Board::startpos();
+        for uci in ["e2e4", "c7c5", "g1f3"].iter() {
+            board.play_uci(uci).unwrap();
+        }
+        assert_eq!(board.fen(), "rnbqkbnr/pp1ppppp/8/2p5/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2");
+        assert_eq!(board.ply(), 3);
+    }
+
+    #[test]
+    fn fen_drops_uncapturable_en_passant() {
+        let mut board = Board::startpos();
+        board.play_uci("e2e4").unwrap();
+        assert_eq!(board.fen(), "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        assert_eq!(Board::from_fen("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1").unwrap().fen(), board.fen());
+    }
+
+    #[test]
+    fn rejects_bad_fens() {
+        assert!(Board::from_fen("").is_err());
+        assert!(Board::from_fen("8/8/8/8/8/8/8 w - - 0 1").is_err());
+        assert!(Board::from_fen("rnbqkbnr/pppppppp/9/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1").is_err());
+        assert!(Board::from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR x KQkq - 0 1").is_err());
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::PositionInfo;
+use crate::uci::INFO_RESP;
+
+pub const DEFAULT_CACHE_SIZE: usize = 100000;
+pub const DEFAULT_CACHE_TTL_HOURS: u64 = 24*30;
+const COMPACT_SLACK: usize = 1000;
+
+static REWRITES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Deserialize, Serialize)]
+struct CacheRecord {
+    key: String,
+    fetched_at: u64,
+    position: PositionInfo,
+}
+
+struct CacheEntry {
+    fetched_at: u64,
+    position: Arc<PositionInfo>,
+}
+
+// A max_entries of 0 means no limit, and a ttl of 0 means entries never expire.
+pub struct PositionCache {
+    path: Option<PathBuf>,
+    max_entries: usize,
+    ttl: u64,
+    entries: Option<HashMap<String, CacheEntry>>,
+    file_records: usize,
+}
+
+pub fn default_cache_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => match env::var_os("HOME").or_else(|| env::var_os("LOCALAPPDATA")) {
+            Some(home) => PathBuf::from(home).join(".cache"),
+            None => return None
+        }
+    };
+    Some(dir.join("lichessdbfish").join("explorer.jsonl"))
+}
+
+fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0
+    }
+}
+
+impl PositionCache {
+    pub fn new(path: Option<PathBuf>, max_entries: usize, ttl_hours: u64) -> PositionCache {
+        PositionCache {
+            path,
+            max_entries,
+            ttl: ttl_hours*3600,
+            entries: None,
+            file_records: 0,
+        }
+    }
+
+    pub fn path(&self) -> Option<&PathBuf> {
+        self.path.as_ref()
+    }
+
+    pub fn set_path(&mut self, path: Option<PathBuf>) {
+        if path != self.path {
+            self.path = path;
+            self.entries = None;
+        }
+    }
+
+    pub fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+        self.evict();
+    }
+
+    pub fn ttl_hours(&self) -> u64 {
+        self.ttl/3600
+    }
+
+    pub fn set_ttl_hours(&mut self, ttl_hours: u64) {
+        self.ttl = ttl_hours*3600;
+    }
+
+    // Forgets every position, on disk as well.
+    pub fn clear(&mut self) {
+        let entries = HashMap::new();
+        if let Some(path) = &self.path {
+            if path.exists() {
+                if let Err(err) = rewrite_records(path, &entries) {
+                    println!("{} string Cannot write explorer cache {}: {}", INFO_RESP, path.display(), err);
+                }
+            }
+        }
+        self.entries = Some(entries);
+        self.file_records = 0;
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<Arc<PositionInfo>> {
+        let ttl = self.ttl;
+        let entries = self.entries();
+        let expired = match entries.get(key) {
+            Some(entry) => ttl != 0 && now().saturating_sub(entry.fetched_at) > ttl,
+            None => return None
+        };
+        if expired {
+            entries.remove(key);
+            None
+        } else {
+            entries.get(key).map(|entry| entry.position.clone())
+        }
+    }
+
+    pub fn insert(&mut self, key: &str, position: Arc<PositionInfo>) {
+        let fetched_at = now();
+        if let Some(path) = &self.path {
+            let record = CacheRecord {
+                key: key.to_string(),
+                fetched_at,
+                position: PositionInfo::clone(&position),
+            };
+            match append_record(path, &record) {
+                Ok(()) => self.file_records += 1,
+                Err(err) => println!("{} string Cannot write explorer cache {}: {}", INFO_RESP, path.display(), err)
+            }
+        }
+        self.entries().insert(key.to_string(), CacheEntry { fetched_at, position });
+        self.evict();
+    }
+
+    fn entries(&mut self) -> &mut HashMap<String, CacheEntry> {
+        if self.entries.is_none() {
+            let mut entries = HashMap::new();
+            self.file_records = 0;
+            if let Some(path) = &self.path {
+                match load_records(path, &mut entries) {
+                    Ok(records) => self.file_records = records,
+                    Err(err) => println!("{} string Cannot read explorer cache {}: {}", INFO_RESP, path.display(), err)
+                }
+            }
+            self.entries = Some(entries);
+            self.evict();
+        }
+        self.entries.as_mut().unwrap()
+    }
+
+    fn evict(&mut self) {
+        let entries = match self.entries.as_mut() {
+            Some(entries) => entries,
+            None => return
+        };
+        let over = self.max_entries != 0 && entries.len() > self.max_entries;
+        if over {
+            // Evict down to 90% of the limit so the file is not rewritten on every insert.
+            let keep = self.max_entries - self.max_entries/10;
+            let mut ages: Vec<(u64, String)> = entries.iter().map(|(key, entry)| (entry.fetched_at, key.clone())).collect();
+            ages.sort();
+            for (_, key) in ages.iter().take(entries.len() - keep) {
+                entries.remove(key);
+            }
+        }
+
+        // Re-fetched and expired positions leave stale lines in the file, so
+        // it is also compacted once those outnumber the live entries.
+        let stale = self.file_records.saturating_sub(entries.len());
+        if !over && stale <= entries.len().max(COMPACT_SLACK) {
+            return
+        }
+        if let Some(path) = &self.path {
+            match rewrite_records(path, entries) {
+                Ok(()) => self.file_records = entries.len(),
+                Err(err) => println!("{} string Cannot write explorer cache {}: {}", INFO_RESP, path.display(), err)
+            }
+        }
+    }
+}
+
+// Returns how many lines the file has, stale ones included.
+fn load_records(path: &Path, entries: &mut HashMap<String, CacheEntry>) -> io::Result<usize> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err)
+    };
+    let mut records = 0;
+    for line in io::BufReader::new(file).lines() {
+        records += 1;
+        match serde_json::from_str::<CacheRecord>(line?.as_str()) {
+            Ok(record) => {
+                entries.insert(record.key, CacheEntry {
+                    fetched_at: record.fetched_at,
+                    position: Arc::new(record.position),
+                });
+            }
+            Err(_) => continue
+        }
+    }
+    Ok(records)
+}
+
+fn append_record(path: &Path, record: &CacheRecord) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(record)?)
+}
+
+// The temporary file is unique to this process and rewrite, so wrapper
+// instances sharing a cache do not clobber each other's.
+fn rewrite_records(path: &Path, entries: &HashMap<String, CacheEntry>) -> io::Result<()> {
+    let tmp_path = path.with_extension(format!("{}.{}.tmp", process::id(), REWRITES.fetch_add(1, Ordering::SeqCst)));
+    let result = write_records(&tmp_path, entries).and_then(|_| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+fn write_records(tmp_path: &Path, entries: &HashMap<String, CacheEntry>) -> io::Result<()> {
+    if let Some(dir) = tmp_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = io::BufWriter::new(fs::File::create(tmp_path)?);
+    for (key, entry) in entries {
+        let record = CacheRecord {
+            key: key.clone(),
+            fetched_at: entry.fetched_at,
+            position: PositionInfo::clone(&entry.position),
+        };
+        writeln!(file, "{}", serde_json::to_string(&record)?)?;
+    }
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("lichessdbfish-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("explorer.jsonl")
+    }
+
+    fn position(white: u64) -> Arc<PositionInfo> {
+        Arc::new(PositionInfo { white, draws: 0, black: 0, moves: vec![], opening: None, book_weights: false })
+    }
+
+    fn file_lines(path: &Path) -> usize {
+        io::BufReader::new(fs::File::open(path).unwrap()).lines().count()
+    }
+
+    #[test]
+    fn refetched_positions_do_not_grow_the_file() {
+        let path = test_path("refetch");
+        let mut cache = PositionCache::new(Some(path.clone()), 10, 0);
+        for i in 0..3000 {
+            cache.insert("same", position(i));
+        }
+        assert!(file_lines(&path) <= COMPACT_SLACK + 1);
+
+        let mut reloaded = PositionCache::new(Some(path.clone()), 10, 0);
+        assert_eq!(reloaded.get("same").map(|x| x.white), Some(2999));
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn zero_size_means_unlimited() {
+        let path = test_path("unlimited");
+        let mut cache = PositionCache::new(Some(path.clone()), 0, 0);
+        for i in 0..20 {
+            cache.insert(format!("key{}", i).as_str(), position(i));
+        }
+        assert_eq!(file_lines(&path), 20);
+        assert!(cache.get("key0").is_some());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn evicts_oldest_entries() {
+        let path = test_path("evict");
+        let mut cache = PositionCache::new(Some(path.clone()), 10, 0);
+        for i in 0..11 {
+            cache.insert(format!("key{}", i).as_str(), position(i));
+        }
+        assert_eq!(file_lines(&path), 9);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
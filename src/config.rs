@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Example:
+// {
+//     "engine": {
+//         "path": "/usr/local/bin/lc0",
+//         "args": ["--weights=/opt/lc0/net.pb.gz"],
+//         "dir": "/opt/lc0",
+//         "env": { "CUDA_VISIBLE_DEVICES": "0" },
+//         "book_only": false
+//     },
+//     "options": {
+//         "LichessDB_Explorer_URL": "http://localhost:9002",
+//         "LichessDB_Games_GT": 100
+//     }
+// }
+#[derive(Deserialize, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub engine: EngineConfig,
+    #[serde(default)]
+    options: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct EngineConfig {
+    pub path: Option<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub dir: Option<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub book_only: bool,
+}
+
+pub fn default_config_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".config"),
+            None => PathBuf::from(env::var_os("APPDATA")?)
+        }
+    };
+    Some(dir.join("lichessdbfish").join("config.json"))
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Config> {
+        let text = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(text.as_str())?)
+    }
+
+    // Option values may be written as JSON strings, numbers or booleans.
+    pub fn options(&self) -> Vec<(String, String)> {
+        self.options.iter().map(|(name, value)| {
+            let value = match value {
+                serde_json::Value::String(x) => x.clone(),
+                x => x.to_string()
+            };
+            (name.clone(), value)
+        }).collect()
+    }
+}
@@ -0,0 +1,425 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::board::Board;
+use crate::pgn::{GameResult, PgnGame, PgnReader};
+use crate::{Move, PositionInfo, RatingFilter, TimeControlFilter};
+
+pub const DEFAULT_MAX_PLY: usize = 30;
+pub const DEFAULT_MAX_POSITIONS: usize = 250_000;
+
+// Band 0 holds games below 1600 or without ratings; it is only counted when
+// every rating filter is enabled.
+const RATING_BANDS: [u64; 5] = [1600, 1800, 2000, 2200, 2500];
+const BAND_COUNT: usize = RATING_BANDS.len() + 1;
+
+const SPEED_BULLET: usize    = 0;
+const SPEED_BLITZ: usize     = 1;
+const SPEED_RAPID: usize     = 2;
+const SPEED_CLASSICAL: usize = 3;
+const SPEED_COUNT: usize     = 4;
+
+#[derive(Clone, Copy, Default)]
+struct Results {
+    white: u64,
+    draws: u64,
+    black: u64,
+}
+
+struct IndexMove {
+    san: String,
+    counts: [[Results; SPEED_COUNT]; BAND_COUNT],
+}
+
+#[derive(Deserialize, Serialize)]
+struct MoveCounts {
+    band: usize,
+    speed: usize,
+    white: u64,
+    draws: u64,
+    black: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct MoveRecord {
+    uci: String,
+    san: String,
+    counts: Vec<MoveCounts>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct IndexRecord {
+    position: String,
+    moves: Vec<MoveRecord>,
+}
+
+// Builds an index file from PGN games.
+//
+// The index file holds one JSON line per position, sorted by position, and is
+// looked up by binary search, so it is never loaded whole. An import keeps at
+// most `max_positions` positions in memory, each well under a kilobyte, and
+// merges them into the file whenever that fills up. Every merge rewrites the
+// file, so a lichess monthly dump is best imported with a limit as large as
+// memory allows and a small max ply.
+pub struct LocalIndex {
+    path: PathBuf,
+    max_positions: usize,
+    positions: BTreeMap<String, BTreeMap<String, IndexMove>>,
+}
+
+// A sorted index file opened for lookups.
+pub struct IndexFile {
+    file: io::BufReader<fs::File>,
+    len: u64,
+}
+
+pub fn position_key(fen: &str) -> String {
+    fen.split_ascii_whitespace().take(4).collect::<Vec<&str>>().join(" ")
+}
+
+fn rating_band(game: &PgnGame) -> usize {
+    let white = game.tag("WhiteElo").and_then(|x| x.parse::<u64>().ok());
+    let black = game.tag("BlackElo").and_then(|x| x.parse::<u64>().ok());
+    let average = match (white, black) {
+        (Some(white), Some(black)) => (white + black)/2,
+        _ => return 0
+    };
+    RATING_BANDS.iter().rposition(|x| average >= *x).map_or(0, |x| x + 1)
+}
+
+// Same estimate lichess uses: base time plus 40 increments. Games without a
+// time control are taken as over the board classical ones; correspondence
+// games, which lichess marks "-", have no speed.
+fn speed(game: &PgnGame) -> Option<usize> {
+    let estimate = match game.tag("TimeControl") {
+        Some("-") => return None,
+        Some(tc) => {
+            let mut parts = tc.splitn(2, '+');
+            match (parts.next().and_then(|x| x.parse::<u64>().ok()), parts.next().and_then(|x| x.parse::<u64>().ok())) {
+                (Some(base), increment) => base + 40*increment.unwrap_or(0),
+                _ => return Some(SPEED_CLASSICAL)
+            }
+        }
+        None => return Some(SPEED_CLASSICAL)
+    };
+    match estimate {
+        0..=178 => Some(SPEED_BULLET),
+        179..=478 => Some(SPEED_BLITZ),
+        479..=1498 => Some(SPEED_RAPID),
+        _ => Some(SPEED_CLASSICAL)
+    }
+}
+
+fn add_record(moves: &mut BTreeMap<String, IndexMove>, record: IndexRecord) {
+    for MoveRecord { uci, san, counts } in record.moves {
+        let index_move = moves.entry(uci).or_insert_with(|| IndexMove { san, counts: Default::default() });
+        for counts in counts.iter().filter(|x| x.band < BAND_COUNT && x.speed < SPEED_COUNT) {
+            let results = &mut index_move.counts[counts.band][counts.speed];
+            results.white += counts.white;
+            results.draws += counts.draws;
+            results.black += counts.black;
+        }
+    }
+}
+
+fn to_record(position: String, moves: BTreeMap<String, IndexMove>) -> IndexRecord {
+    let moves = moves.into_iter().map(|(uci, index_move)| {
+        let mut counts = vec![];
+        for (band, speeds) in index_move.counts.iter().enumerate() {
+            for (speed, results) in speeds.iter().enumerate().filter(|(_, x)| x.white + x.draws + x.black > 0) {
+                counts.push(MoveCounts { band, speed, white: results.white, draws: results.draws, black: results.black });
+            }
+        }
+        MoveRecord { uci, san: index_move.san, counts }
+    });
+    IndexRecord { position, moves: moves.collect() }
+}
+
+fn next_record<R: BufRead>(lines: &mut Option<io::Lines<R>>) -> io::Result<Option<IndexRecord>> {
+    match lines.as_mut().and_then(|x| x.next()) {
+        Some(line) => Ok(Some(serde_json::from_str(line?.as_str())?)),
+        None => Ok(None)
+    }
+}
+
+impl LocalIndex {
+    pub fn new(path: &Path, max_positions: usize) -> LocalIndex {
+        LocalIndex {
+            path: path.to_path_buf(),
+            max_positions,
+            positions: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, position: String, uci: String, san: &str, band: usize, speed: usize, result: GameResult) {
+        let moves = self.positions.entry(position).or_default();
+        let index_move = moves.entry(uci).or_insert_with(|| IndexMove { san: san.to_string(), counts: Default::default() });
+        let results = &mut index_move.counts[band][speed];
+        match result {
+            GameResult::White => results.white += 1,
+            GameResult::Draw => results.draws += 1,
+            GameResult::Black => results.black += 1,
+        }
+    }
+
+    pub fn add_game(&mut self, game: &PgnGame, max_ply: usize) -> Result<(), String> {
+        let result = match game.result {
+            Some(result) => result,
+            None => return Err("Game has no result".to_string())
+        };
+        let speed = match speed(game) {
+            Some(speed) => speed,
+            None => return Err("Correspondence game".to_string())
+        };
+        let mut board = match (game.tag("SetUp"), game.tag("FEN")) {
+            (Some("1"), Some(fen)) => Board::from_fen(fen)?,
+            _ => Board::startpos()
+        };
+        let band = rating_band(game);
+
+        // Nothing is counted until the whole game parses, so a skipped game
+        // leaves no partial counts behind.
+        let mut plies = vec![];
+        for san in game.moves.iter().take(max_ply) {
+            let chess_move = board.parse_san(san)?;
+            plies.push((position_key(board.fen().as_str()), chess_move.uci(), san.trim_end_matches(&['!', '?'][..])));
+            board.play(chess_move)?;
+        }
+        for (position, uci, san) in plies {
+            self.add(position, uci, san, band, speed, result);
+        }
+        Ok(())
+    }
+
+    pub fn import<R: BufRead>(&mut self, reader: R, max_ply: usize) -> io::Result<(u64, u64)> {
+        let mut imported = 0;
+        let mut skipped = 0;
+        for game in PgnReader::new(reader) {
+            match self.add_game(&game?, max_ply) {
+                Ok(()) => imported += 1,
+                Err(_) => skipped += 1
+            }
+            if self.positions.len() >= self.max_positions {
+                self.flush()?;
+            }
+        }
+        Ok((imported, skipped))
+    }
+
+    // Merges the positions in memory into the index file and returns how many
+    // positions the file holds now.
+    pub fn flush(&mut self) -> io::Result<usize> {
+        let mut old = match fs::File::open(&self.path) {
+            Ok(file) => Some(io::BufReader::new(file).lines()),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err)
+        };
+        let temp_path = self.path.with_extension("tmp");
+        let mut file = io::BufWriter::new(fs::File::create(&temp_path)?);
+        let mut written = 0;
+
+        let mut next_old = next_record(&mut old)?;
+        for (position, mut moves) in std::mem::take(&mut self.positions) {
+            while let Some(record) = next_old.take() {
+                match record.position.cmp(&position) {
+                    Ordering::Less => {
+                        writeln!(file, "{}", serde_json::to_string(&record)?)?;
+                        written += 1;
+                        next_old = next_record(&mut old)?;
+                    }
+                    Ordering::Equal => {
+                        add_record(&mut moves, record);
+                        next_old = next_record(&mut old)?;
+                        break
+                    }
+                    Ordering::Greater => {
+                        next_old = Some(record);
+                        break
+                    }
+                }
+            }
+            writeln!(file, "{}", serde_json::to_string(&to_record(position, moves))?)?;
+            written += 1;
+        }
+        while let Some(record) = next_old {
+            writeln!(file, "{}", serde_json::to_string(&record)?)?;
+            written += 1;
+            next_old = next_record(&mut old)?;
+        }
+
+        file.flush()?;
+        drop(file);
+        fs::rename(&temp_path, &self.path)?;
+        Ok(written)
+    }
+}
+
+impl IndexFile {
+    pub fn open(path: &Path) -> io::Result<IndexFile> {
+        let file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok(IndexFile { file: io::BufReader::new(file), len })
+    }
+
+    // The first record starting at or after offset, with where it starts and
+    // how long its line is.
+    fn record_at(&mut self, offset: u64) -> io::Result<Option<(u64, u64, IndexRecord)>> {
+        let mut line = String::new();
+        let start = match offset {
+            0 => {
+                self.file.seek(SeekFrom::Start(0))?;
+                0
+            }
+            _ => {
+                self.file.seek(SeekFrom::Start(offset - 1))?;
+                offset - 1 + self.file.read_line(&mut line)? as u64
+            }
+        };
+        line.clear();
+        match self.file.read_line(&mut line)? {
+            0 => Ok(None),
+            n => Ok(Some((start, n as u64, serde_json::from_str(line.as_str())?)))
+        }
+    }
+
+    fn find(&mut self, position: &str) -> io::Result<Option<IndexRecord>> {
+        let mut low = 0;
+        let mut high = self.len;
+        while low < high {
+            let middle = low + (high - low)/2;
+            match self.record_at(middle)? {
+                Some((start, len, record)) if start < high => match record.position.as_str().cmp(position) {
+                    Ordering::Less => low = start + len,
+                    Ordering::Equal => return Ok(Some(record)),
+                    Ordering::Greater => high = middle
+                },
+                _ => high = middle
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn position_info(&mut self, fen: &str, ratings: &RatingFilter, tc: &TimeControlFilter) -> io::Result<PositionInfo> {
+        let all_ratings = ratings.rating_1600 && ratings.rating_1800 && ratings.rating_2000 && ratings.rating_2200 && ratings.rating_2500;
+        let bands = [all_ratings, ratings.rating_1600, ratings.rating_1800, ratings.rating_2000, ratings.rating_2200, ratings.rating_2500];
+        let speeds = [tc.bullet, tc.blitz, tc.rapid, tc.classical];
+
+        let mut position = PositionInfo { white: 0, draws: 0, black: 0, moves: vec![], opening: None, book_weights: false };
+        let record = match self.find(position_key(fen).as_str())? {
+            Some(record) => record,
+            None => return Ok(position)
+        };
+        for move_record in record.moves {
+            let mut result = Move { uci: move_record.uci, san: move_record.san, white: 0, draws: 0, black: 0, average_rating: None };
+            for counts in move_record.counts.iter().filter(|x| bands.get(x.band) == Some(&true) && speeds.get(x.speed) == Some(&true)) {
+                result.white += counts.white;
+                result.draws += counts.draws;
+                result.black += counts.black;
+            }
+            if result.white + result.draws + result.black > 0 {
+                position.white += result.white;
+                position.draws += result.draws;
+                position.black += result.black;
+                position.moves.push(result);
+            }
+        }
+        position.moves.sort_by_key(|x| Reverse(x.white + x.draws + x.black));
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    const STARTPOS: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+    const E4: &str = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1";
+
+    fn all_ratings() -> RatingFilter {
+        RatingFilter { rating_1600: true, rating_1800: true, rating_2000: true, rating_2200: true, rating_2500: true }
+    }
+
+    fn all_speeds() -> TimeControlFilter {
+        TimeControlFilter { bullet: true, blitz: true, rapid: true, classical: true }
+    }
+
+    fn test_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("lichessdbfish-test-{}-{}.jsonl", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn import(path: &Path, pgn: &str, max_positions: usize) -> (IndexFile, u64, u64) {
+        let mut index = LocalIndex::new(path, max_positions);
+        let (imported, skipped) = index.import(pgn.as_bytes(), DEFAULT_MAX_PLY).unwrap();
+        index.flush().unwrap();
+        (IndexFile::open(path).unwrap(), imported, skipped)
+    }
+
+    #[test]
+    fn counts_results_per_move() {
+        let path = test_path("counts");
+        let (mut index, imported, skipped) = import(&path, "1. e4 e5 1-0\n\n1. e4 c5 0-1\n\n1. d4 d5 1/2-1/2\n", DEFAULT_MAX_POSITIONS);
+        assert_eq!((imported, skipped), (3, 0));
+        let info = index.position_info(STARTPOS, &all_ratings(), &all_speeds()).unwrap();
+        assert_eq!((info.white, info.draws, info.black), (1, 1, 1));
+        assert_eq!(info.moves[0].uci, "e2e4");
+        assert_eq!(info.moves[0].san, "e4");
+        assert_eq!((info.moves[0].white, info.moves[0].black), (1, 1));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn skipped_games_leave_no_counts() {
+        let path = test_path("skipped");
+        let (_, imported, skipped) = import(&path, "1. e4 e5 2. Qxf7 1-0\n\n1. e4 *\n\n[TimeControl \"-\"]\n\n1. e4 1-0\n", DEFAULT_MAX_POSITIONS);
+        assert_eq!((imported, skipped), (0, 3));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn merges_into_the_index_file() {
+        let path = test_path("merge");
+        // A limit of one position merges into the file after every game.
+        import(&path, "1. e4 e5 1-0\n\n1. d4 d5 0-1\n\n1. e4 c5 1-0\n", 1);
+        let (mut index, _, _) = import(&path, "1. e4 e5 1/2-1/2\n\n1. c4 1-0\n", DEFAULT_MAX_POSITIONS);
+
+        let info = index.position_info(STARTPOS, &all_ratings(), &all_speeds()).unwrap();
+        assert_eq!((info.white, info.draws, info.black), (3, 1, 1));
+        assert_eq!(info.moves.iter().map(|x| x.uci.as_str()).collect::<Vec<&str>>(), vec!["e2e4", "c2c4", "d2d4"]);
+        let info = index.position_info(E4, &all_ratings(), &all_speeds()).unwrap();
+        assert_eq!(info.moves.iter().map(|x| (x.uci.as_str(), x.white, x.draws)).collect::<Vec<_>>(), vec![("e7e5", 1, 1), ("c7c5", 1, 0)]);
+        assert!(index.position_info("8/8/8/8/8/8/8/K6k w - - 0 1", &all_ratings(), &all_speeds()).unwrap().moves.is_empty());
+
+        // Every record is found by the binary search.
+        for line in fs::read_to_string(&path).unwrap().lines() {
+            let record: IndexRecord = serde_json::from_str(line).unwrap();
+            assert_eq!(index.find(record.position.as_str()).unwrap().map(|x| x.position), Some(record.position));
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn filters_by_rating_and_speed() {
+        let path = test_path("filters");
+        let (mut index, _, _) = import(&path, "[WhiteElo \"2100\"]\n[BlackElo \"2100\"]\n[TimeControl \"60+0\"]\n\n1. e4 1-0\n\n[TimeControl \"600+5\"]\n\n1. d4 0-1\n", DEFAULT_MAX_POSITIONS);
+        let mut ratings = all_ratings();
+        let mut speeds = all_speeds();
+        assert_eq!(index.position_info(STARTPOS, &ratings, &speeds).unwrap().moves.len(), 2);
+
+        // Unrated games only count when every rating band is enabled.
+        ratings.rating_1600 = false;
+        let info = index.position_info(STARTPOS, &ratings, &speeds).unwrap();
+        assert_eq!(info.moves.len(), 1);
+        assert_eq!(info.moves[0].uci, "e2e4");
+
+        speeds.bullet = false;
+        assert!(index.position_info(STARTPOS, &ratings, &speeds).unwrap().moves.is_empty());
+        let _ = fs::remove_file(&path);
+    }
+}
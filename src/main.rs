@@ -5,35 +5,87 @@ extern crate serde_derive;
 extern crate serde_json;
 extern crate rand;
 
+mod board;
+mod cache;
+mod config;
+mod index;
+mod options;
+mod pgn;
+mod polyglot;
+mod source;
+mod supervisor;
+mod uci;
+
 use std::io::{self, BufRead, Write};
-use std::process;
+use std::env;
+use std::fs;
 use std::iter;
-use std::collections::HashMap;
-use std::rc::Rc;
-use std::borrow::Borrow;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryFrom;
+use std::cmp::Reverse;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 
+use board::{Board, ChessMove, Color};
+use cache::PositionCache;
+use index::LocalIndex;
+use polyglot::PolyglotEntry;
+use source::{ExplorerError, Filters, SourceKind, Sources};
+use config::Config;
+use supervisor::{EngineCommand, Supervisor};
+use uci::{Command, Go, Position, Response, Score, EXIT_CMD, INFO_RESP, ISREADY_CMD, MULTIPV_OPTION, NO_MOVE, NULL_MOVE, PONDERHIT_CMD, QUIT_CMD,
+    READYOK_RESP, STOP_CMD, UCINEWGAME_CMD, UCIOK_RESP, UCI_CMD};
+
+const ENGINE_NAME: &str = "Lichessdbfish";
 const STARTPOS: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
 
-const UCI_CMD: &str       = "uci";
-const SETOPTION_CMD: &str = "setoption";
-const ISREADY_CMD: &str   = "isready";
-const POSITION_CMD: &str  = "position";
-const GO_CMD: &str        = "go";
-const STOP_CMD: &str      = "stop";
-const QUIT_CMD: &str      = "quit";
-const EXIT_CMD: &str      = "exit";
+const NONE_VALUE: &str = "None";
+
+const IMPORT_PGN_SUBCMD: &str      = "import-pgn";
+const EXPORT_POLYGLOT_SUBCMD: &str = "export-polyglot";
+const MAX_PLY_FLAG: &str           = "--max-ply";
+const MAX_POSITIONS_FLAG: &str     = "--max-positions";
+const FEN_FLAG: &str               = "--fen";
+const COLOR_FLAG: &str             = "--color";
+const DEPTH_FLAG: &str             = "--depth";
+const SET_FLAG: &str               = "--set";
+const CONFIG_FLAG: &str            = "--config";
+const ENGINE_FLAG: &str            = "--engine";
+const ENGINE_ARG_FLAG: &str        = "--engine-arg";
+const ENGINE_DIR_FLAG: &str        = "--engine-dir";
+const ENGINE_ENV_FLAG: &str        = "--engine-env";
+const BOOK_ONLY_FLAG: &str         = "--book-only";
+const STDIN_PATH: &str             = "-";
 
-const UCIOK_RESP: &str    = "uciok";
-const OPTION_RESP: &str   = "option";
-const READYOK_RESP: &str  = "readyok";
-const INFO_RESP: &str     = "info";
-const BESTMOVE_RESP: &str = "bestmove";
+const CONFIG_ENV: &str      = "LICHESSDBFISH_CONFIG";
+const ENGINE_ENV: &str      = "LICHESSDBFISH_ENGINE";
+const ENGINE_ARGS_ENV: &str = "LICHESSDBFISH_ENGINE_ARGS";
+const ENGINE_DIR_ENV: &str  = "LICHESSDBFISH_ENGINE_DIR";
 
-const NAME_PARAM: &str    = "name";
-const VALUE_PARAM: &str   = "value";
+const DEFAULT_ENGINE: &str = "stockfish";
+
+const DEFAULT_EXPORT_DEPTH: usize = 12;
+const DEFAULT_NETWORK_BACKOFF: u64 = 60;
+const DEFAULT_PREFETCH: usize = 3;
+const DEFAULT_MAX_EVAL_DROP: i64 = 50;
+const DEFAULT_EVAL_DEPTH: u32 = 12;
+const MATE_SCORE: i64 = 100000;
+const DEFAULT_ENGINE_TIMEOUT: u64 = 30000;
+const MAX_ENGINE_RESTARTS: u32 = 3;
+const DEFAULT_MOVES_TO_GO: u64 = 30;
+const MIN_EVAL_TIME: u64 = 50;
+const MIN_ENGINE_SILENCE: u64 = 5000;
+const MAX_EXPORT_RETRIES: u32 = 5;
 
 struct Engine {
+    source: SourceKind,
+    fallback_sources: Vec<SourceKind>,
     master_games: bool,
     ratings: RatingFilter,
     tc: TimeControlFilter,
@@ -44,12 +96,86 @@ struct Engine {
     opt_sortby: SortBy,
     opt_variants: usize,
     opt_weightby: WeightBy,
+    opt_network_backoff: u64,
+    opt_request_timeout: u64,
+    opt_prefetch: usize,
+    opt_eval_guard: bool,
+    opt_max_eval_drop: i64,
+    opt_eval_depth: u32,
+    opt_book_exit: BookExit,
+    opt_max_ply: u32,
+    opt_min_time: u64,
+    opt_out_of_book: OutOfBook,
+    opt_engine_timeout: u64,
+    book_only: bool,
 
+    board: Board,
     fen: String,
     turn: Turn,
-    cache: HashMap<String, Rc<PositionInfo>>,
+    multipv: usize,
+    debug: bool,
+    opt_seed: u64,
+    rng: StdRng,
+    book_exit_ply: Option<u32>,
+    // Why the last position could not be set up; the book is not used for it.
+    position_error: Option<String>,
+    // The search to resume after an engine restart, and when our clock
+    // started running for it.
+    pending_go: Option<(Go, Instant)>,
+    explorer: Arc<Mutex<Explorer>>,
+    lookup_id: Arc<AtomicU64>,
+}
+
+// Explorer state shared with the lookup thread.
+struct Explorer {
+    cache: PositionCache,
+    sources: Sources,
+    offline_until: Option<Instant>,
+    // Lookups for a go still running; prefetches wait for them.
+    live_lookups: usize,
+}
+
+// Snapshot of the settings a single lookup needs.
+#[derive(Clone)]
+struct Lookup {
+    board: Board,
+    ratings: RatingFilter,
+    tc: TimeControlFilter,
+    primary: SourceKind,
+    fallbacks: Vec<SourceKind>,
+    network_backoff: u64,
+    timeout: Duration,
+    cache_only: bool,
+    prefetch: bool,
+    debug: bool,
+}
+
+#[derive(Clone)]
+struct SearchInfo {
+    depth: u32,
+    seldepth: u32,
+    score: Score,
+    pv: Vec<String>,
+}
+
+enum Event {
+    Input(String),
+    InputExit,
+    EngineLine(String),
+    EngineExit,
+    Book(u64, Option<Arc<PositionInfo>>),
 }
 
+// GUI input that arrives while waiting for the engine is queued and handled
+// by the main loop afterwards.
+struct EventQueue {
+    sender: mpsc::Sender<Event>,
+    receiver: mpsc::Receiver<Event>,
+    input: VecDeque<String>,
+    engine_exited: bool,
+}
+
+#[derive(Clone)]
 struct RatingFilter {
     rating_1600: bool,
     rating_1800: bool,
@@ -58,6 +184,7 @@ struct RatingFilter {
     rating_2500: bool,
 }
 
+#[derive(Clone)]
 struct TimeControlFilter {
     bullet: bool,
     blitz: bool,
@@ -76,142 +203,375 @@ enum WeightBy {
     Random,
 }
 
+// What happens after a position with no qualifying book move: keep asking,
+// stay out of book for the rest of the game, or only come back when the
+// cache or a local book knows a transposition.
+#[derive(Clone, Copy, PartialEq)]
+enum BookExit {
+    Off,
+    Latch,
+    Transpositions,
+}
+
+// The answer in book-only mode when the explorer has no move.
+enum OutOfBook {
+    Resign,
+    Random,
+    Null,
+}
+
 enum Turn {
     White,
     Black,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 struct Move {
     uci: String,
     san: String,
     white: u64,
     draws: u64,
     black: u64,
+    #[serde(rename = "averageRating", default, skip_serializing_if = "Option::is_none")]
+    average_rating: Option<u64>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
+struct Opening {
+    eco: String,
+    name: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 struct PositionInfo {
     white: u64,
     draws: u64,
     black: u64,
     moves: Vec<Move>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    opening: Option<Opening>,
+    // Polyglot weights are not game counts, so LichessDB_Games_GT does not apply to them.
+    #[serde(skip)]
+    book_weights: bool,
 }
 
-fn fix_castle(lichess_move: &String) -> String {
-    match lichess_move.as_str() {
+fn fix_castle(lichess_move: &str) -> String {
+    match lichess_move {
         "e1h1" => "e1g1",
         "e1a1" => "e1c1",
         "e8h8" => "e8g8",
         "e8a8" => "e8c8",
-        _ => lichess_move.as_str()
+        _ => lichess_move
     }.to_string()
 }
 
-fn get_position_info(fen: &str, master_games: bool, ratings: &RatingFilter, tc: &TimeControlFilter) -> Result<PositionInfo, String> {
-    let mut params = vec![("fen", fen), ("moves", "50")];
+fn lock(explorer: &Mutex<Explorer>) -> MutexGuard<'_, Explorer> {
+    match explorer.lock() {
+        Ok(explorer) => explorer,
+        Err(poisoned) => poisoned.into_inner()
+    }
+}
 
-    let url_str = if master_games {
-        "https://explorer.lichess.ovh/master"
-    } else {
-        params.push(("variant", "standard"));
-        if ratings.rating_1600 {
-            params.push(("ratings[]", "1600"));
-        }
-        if ratings.rating_1800 {
-            params.push(("ratings[]", "1800"));
+fn lock_explorer(engine: &Engine) -> MutexGuard<'_, Explorer> {
+    lock(&engine.explorer)
+}
+
+fn get_lookup(engine: &Engine, timeout: Duration) -> Lookup {
+    Lookup {
+        board: engine.board.clone(),
+        ratings: engine.ratings.clone(),
+        tc: engine.tc.clone(),
+        primary: match engine.source {
+            SourceKind::Lichess if engine.master_games => SourceKind::Masters,
+            kind => kind
+        },
+        fallbacks: engine.fallback_sources.clone(),
+        network_backoff: engine.opt_network_backoff,
+        timeout,
+        cache_only: engine.opt_book_exit == BookExit::Transpositions && engine.book_exit_ply.is_some(),
+        prefetch: false,
+        debug: engine.debug,
+    }
+}
+
+// The explorer is only locked around the cache; network requests run on a
+// copy of the source so other lookups are not held up behind them.
+fn get_position_info_cached(explorer: &Mutex<Explorer>, lookup: &Lookup, kind: SourceKind) -> Result<Arc<PositionInfo>, ExplorerError> {
+    let filters = Filters { ratings: &lookup.ratings, tc: &lookup.tc };
+    let (key, remote) = {
+        let mut explorer = lock(explorer);
+        // Only sources with a cache key go over the network.
+        let key = match explorer.sources.get(kind).cache_key(&lookup.board, &filters) {
+            Some(key) => key,
+            None => return get_local_position_info(&mut explorer, lookup, kind)
+        };
+        if let Some(position) = explorer.cache.get(key.as_str()) {
+            if lookup.debug {
+                println!("{} string {} explorer: cache hit, {} moves", INFO_RESP, kind.name(), position.moves.len());
+            }
+            return Ok(position)
         }
-        if ratings.rating_2000 {
-            params.push(("ratings[]", "2000"));
+        if lookup.cache_only {
+            return Err(ExplorerError::Unavailable("Out of book, not in cache".to_string()))
         }
-        if ratings.rating_2200 {
-            params.push(("ratings[]", "2200"));
+        if lookup.prefetch && explorer.live_lookups > 0 {
+            return Err(ExplorerError::Unavailable("Prefetch yields to a live lookup".to_string()))
         }
-        if ratings.rating_2500 {
-            params.push(("ratings[]", "2500"));
+        if let Some(until) = explorer.offline_until {
+            let now = Instant::now();
+            if now < until {
+                return Err(ExplorerError::Unavailable(format!("Network backoff, retrying in {}s", (until - now).as_secs() + 1)))
+            }
+            explorer.offline_until = None;
         }
+        (key, explorer.sources.remote(kind, lookup.timeout))
+    };
+    let mut source = match remote {
+        Some(source) => source,
+        None => return Err(ExplorerError::Unavailable(format!("{} is not a network source", kind.name())))
+    };
 
-        if tc.bullet {
-            params.push(("speeds[]", "bullet"));
-        }
-        if tc.blitz {
-            params.push(("speeds[]", "blitz"));
+    let started = Instant::now();
+    let position = match source.position_info(&lookup.board, &filters) {
+        Ok(position) => {
+            if lookup.debug {
+                println!("{} string {} explorer: {} moves in {}ms", INFO_RESP, kind.name(), position.moves.len(), started.elapsed().as_millis());
+            }
+            Arc::new(position)
         }
-        if tc.rapid {
-            params.push(("speeds[]", "rapid"));
+        Err(err) => {
+            // A timeout only means this move's time budget ran out, so it does
+            // not back off; neither does a failed prefetch.
+            let backoff = match err {
+                _ if lookup.prefetch => None,
+                ExplorerError::RateLimited(Some(retry_after)) => Some(retry_after),
+                ExplorerError::RateLimited(None) | ExplorerError::Http(_) | ExplorerError::Status(_) if lookup.network_backoff > 0 => Some(Duration::from_secs(lookup.network_backoff)),
+                _ => None
+            };
+            if let Some(backoff) = backoff {
+                lock(explorer).offline_until = Some(Instant::now() + backoff);
+            }
+            return Err(err)
         }
-        if tc.classical {
-            params.push(("speeds[]", "classical"));
+    };
+    lock(explorer).cache.insert(key.as_str(), position.clone());
+    Ok(position)
+}
+
+fn get_local_position_info(explorer: &mut Explorer, lookup: &Lookup, kind: SourceKind) -> Result<Arc<PositionInfo>, ExplorerError> {
+    let filters = Filters { ratings: &lookup.ratings, tc: &lookup.tc };
+    let started = Instant::now();
+    let position = explorer.sources.get(kind).position_info(&lookup.board, &filters)?;
+    if lookup.debug {
+        println!("{} string {} explorer: {} moves in {}ms", INFO_RESP, kind.name(), position.moves.len(), started.elapsed().as_millis());
+    }
+    Ok(Arc::new(position))
+}
+
+// The primary source, then each fallback in order, until one has moves.
+fn get_source_position_info(explorer: &Mutex<Explorer>, lookup: &Lookup) -> Option<Arc<PositionInfo>> {
+    let mut result = None;
+    for kind in iter::once(lookup.primary).chain(lookup.fallbacks.iter().cloned()) {
+        match get_position_info_cached(explorer, lookup, kind) {
+            Ok(position) => {
+                let found = !position.moves.is_empty();
+                result = Some(position);
+                if found {
+                    break
+                }
+            }
+            Err(err) => println!("{} string {} explorer: {}", INFO_RESP, kind.name(), err)
         }
+    }
+    result
+}
 
-        "https://explorer.lichess.ovh/lichess"
-    };
+// The answer is tagged with the lookup id so a late reply to an earlier go is ignored.
+fn spawn_lookup(engine: &mut Engine, timeout: Duration, events: mpsc::Sender<Event>) {
+    let id = engine.lookup_id.fetch_add(1, Ordering::SeqCst) + 1;
+    let lookup = get_lookup(engine, timeout);
+    let explorer = engine.explorer.clone();
+    lock(&explorer).live_lookups += 1;
+    thread::spawn(move || {
+        let position = get_source_position_info(&explorer, &lookup);
+        lock(&explorer).live_lookups -= 1;
+        let _ = events.send(Event::Book(id, position));
+    });
+}
 
-    let url = match reqwest::Url::parse_with_params(url_str, &params) {
-        Ok(url) => url,
-        Err(err) => return Err(format!("Url parse error: {}", err))
+// The most popular reply to a book move, if the explorer already knows it
+// without going to the network.
+fn get_book_reply(engine: &Engine, book_move: &Move) -> Option<String> {
+    let mut lookup = get_lookup(engine, Duration::from_millis(engine.opt_request_timeout));
+    lookup.board.play_uci(book_move.uci.as_str()).ok()?;
+    let mut explorer = engine.explorer.try_lock().ok()?;
+    let filters = Filters { ratings: &lookup.ratings, tc: &lookup.tc };
+    let position = match explorer.sources.get(lookup.primary).cache_key(&lookup.board, &filters) {
+        Some(key) => explorer.cache.get(key.as_str())?,
+        None => Arc::new(explorer.sources.get(lookup.primary).position_info(&lookup.board, &filters).ok()?)
     };
-    let text = match reqwest::get(url) {
-        Ok(mut resp) => match resp.text() {
-            Ok(text) => text,
-            Err(err) => return Err(format!("HTTP response body error: {}", err))
-        },
-        Err(err) => return Err(format!("HTTP request error: {}", err))
-    };
-    match serde_json::from_str(&text) {
-        Ok(position) => Ok(position),
-        Err(err) => return Err(format!("JSON parse error: {}", err))
+    let legal_moves = lookup.board.legal_moves();
+    position.moves.iter()
+        .filter(|x| ChessMove::from_uci(fix_castle(&x.uci).as_str()).is_ok_and(|x| legal_moves.contains(&x)))
+        .max_by_key(|x| x.white + x.draws + x.black)
+        .map(|x| fix_castle(&x.uci))
+}
+
+// Quietly warms the cache; sources that are not cached are not worth prefetching.
+fn prefetch_position(explorer: &Mutex<Explorer>, lookup: &Lookup) -> Option<Arc<PositionInfo>> {
+    let filters = Filters { ratings: &lookup.ratings, tc: &lookup.tc };
+    lock(explorer).sources.get(lookup.primary).cache_key(&lookup.board, &filters)?;
+    get_position_info_cached(explorer, lookup, lookup.primary).ok()
+}
+
+// After a book move, fetch the position it leads to and the positions after
+// the most popular replies while the opponent thinks. The next lookup id
+// cancels whatever is left, and no request starts while a go is looking up.
+fn spawn_prefetch(engine: &Engine, book_move: &Move) {
+    if engine.opt_prefetch == 0 {
+        return
     }
+    let mut lookup = get_lookup(engine, Duration::from_millis(engine.opt_request_timeout));
+    if lookup.board.play_uci(book_move.uci.as_str()).is_err() {
+        return
+    }
+    lookup.prefetch = true;
+    let replies = engine.opt_prefetch;
+    let explorer = engine.explorer.clone();
+    let lookup_id = engine.lookup_id.clone();
+    let id = lookup_id.load(Ordering::SeqCst);
+    thread::spawn(move || {
+        let position = match prefetch_position(&explorer, &lookup) {
+            Some(position) => position,
+            None => return
+        };
+        let mut moves: Vec<&Move> = position.moves.iter().collect();
+        moves.sort_by_key(|x| Reverse(x.white + x.draws + x.black));
+        for x in moves.iter().take(replies) {
+            if lookup_id.load(Ordering::SeqCst) != id {
+                return
+            }
+            let mut next = lookup.clone();
+            if next.board.play_uci(fix_castle(&x.uci).as_str()).is_ok() {
+                prefetch_position(&explorer, &next);
+            }
+        }
+    });
 }
 
-fn get_position_info_cached(fen: &str, master_games: bool, ratings: &RatingFilter, tc: &TimeControlFilter, cache: &mut HashMap<String, Rc<PositionInfo>>) -> Option<Rc<PositionInfo>> {
-    match cache.get(fen) {
-        Some(position) => Some(position.clone()),
-        None => {
-            let position = match get_position_info(fen, master_games, ratings, tc) {
-                Ok(position) => Rc::new(position),
-                Err(_) => {
+impl EventQueue {
+    fn new() -> EventQueue {
+        let (sender, receiver) = mpsc::channel();
+        EventQueue {
+            sender,
+            receiver,
+            input: VecDeque::new(),
+            engine_exited: false,
+        }
+    }
+
+    // Gives up after waiting timeout for a line, if there is a timeout.
+    fn engine_line(&mut self, timeout: Option<Duration>) -> Option<String> {
+        let deadline = timeout.map(|x| Instant::now() + x);
+        loop {
+            let event = match deadline {
+                Some(deadline) => self.receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())).ok()?,
+                None => self.receiver.recv().ok()?
+            };
+            match event {
+                Event::EngineLine(line) => return Some(line),
+                Event::Input(line) => self.input.push_back(line),
+                Event::InputExit => self.input.push_back(QUIT_CMD.to_string()),
+                Event::Book(_, _) => (),
+                Event::EngineExit => {
+                    self.engine_exited = true;
                     return None
                 }
-            };
-            let result = position.clone();
-            cache.insert(fen.to_string(), position);
-            Some(result)
+            }
+        }
+    }
+
+    // Drops whatever a stopped engine and the lookups of an abandoned go left
+    // behind, keeping the GUI input.
+    fn discard_engine_events(&mut self) {
+        while let Ok(event) = self.receiver.try_recv() {
+            match event {
+                Event::Input(line) => self.input.push_back(line),
+                Event::InputExit => self.input.push_back(QUIT_CMD.to_string()),
+                _ => ()
+            }
+        }
+        self.engine_exited = false;
+    }
+
+    fn next_input(&mut self) -> Option<String> {
+        if let Some(line) = self.input.pop_front() {
+            return Some(line)
+        }
+        loop {
+            match self.receiver.recv() {
+                Ok(Event::Input(line)) => return Some(line),
+                Ok(Event::EngineLine(_)) | Ok(Event::Book(_, _)) => (),
+                Ok(Event::EngineExit) => self.engine_exited = true,
+                Ok(Event::InputExit) | Err(_) => return None
+            }
         }
     }
 }
 
-fn get_position_move(engine: &Engine, position: &PositionInfo, mut rng: impl rand::Rng) -> Option<Move> {
+fn get_move_weight(engine: &Engine, x: &Move) -> u64 {
+    match engine.opt_weightby {
+        WeightBy::Games => x.white + x.draws + x.black,
+        WeightBy::Score => (2*(if let Turn::White = engine.turn { x.white } else { x.black }) + x.draws)*100/(2*x.white + x.black + 2*x.draws).max(1),
+        WeightBy::Random => 1
+    }
+}
+
+fn is_legal_move(engine: &Engine, x: &Move) -> bool {
+    match ChessMove::from_uci(fix_castle(&x.uci).as_str()) {
+        Ok(chess_move) => engine.board.legal_moves().contains(&chess_move),
+        Err(_) => false
+    }
+}
+
+fn get_move_score_pct(engine: &Engine, x: &Move) -> u64 {
+    (2*(if let Turn::White = engine.turn { x.white } else { x.black }) + x.draws)*50/(x.white + x.draws + x.black).max(1)
+}
+
+// Every move that passes the thresholds, best first. Moves outside a
+// non-empty searchmoves are left out.
+fn get_position_moves<'a>(engine: &Engine, position: &'a PositionInfo, searchmoves: &[String]) -> Vec<&'a Move> {
     let mut moves: Vec<&Move> = position.moves.iter()
-        .filter(|x| x.white + x.black + x.draws >= engine.opt_games_min)
-        .filter(|x| (x.white + x.black + x.draws)*100/(position.white + position.draws + position.black) >= engine.opt_games_pct_min)
-        .filter(|x| (2*(if let Turn::White = engine.turn { x.white } else { x.black }) + x.draws)*100/(2*x.white + x.black + 2*x.draws) >= engine.opt_score_pct_min)
+        .filter(|x| position.book_weights || x.white + x.black + x.draws >= engine.opt_games_min)
+        .filter(|x| (x.white + x.black + x.draws)*100/(position.white + position.draws + position.black).max(1) >= engine.opt_games_pct_min)
+        .filter(|x| (2*(if let Turn::White = engine.turn { x.white } else { x.black }) + x.draws)*100/(2*x.white + x.black + 2*x.draws).max(1) >= engine.opt_score_pct_min)
+        .filter(|x| is_legal_move(engine, x))
+        .filter(|x| searchmoves.is_empty() || searchmoves.contains(&fix_castle(&x.uci)))
         .collect();
 
     moves.sort_by(|x, y| {
         match &engine.opt_sortby {
             SortBy::Games => (y.white + y.black + y.draws).cmp(&(x.white + x.black + x.draws)),
-            SortBy::Score => ((2*(if let Turn::White = engine.turn { y.white } else { y.black }) + y.draws)*100/(2*y.white + y.black + 2*y.draws)).cmp(&((2*(if let Turn::White = engine.turn { x.white } else { x.black }) + x.draws)*100/(2*x.white + x.black + 2*x.draws))),
+            SortBy::Score => ((2*(if let Turn::White = engine.turn { y.white } else { y.black }) + y.draws)*100/(2*y.white + y.black + 2*y.draws).max(1)).cmp(&((2*(if let Turn::White = engine.turn { x.white } else { x.black }) + x.draws)*100/(2*x.white + x.black + 2*x.draws).max(1))),
         }
     });
+    moves
+}
 
+fn get_position_candidates<'a>(engine: &Engine, position: &'a PositionInfo, searchmoves: &[String]) -> Vec<&'a Move> {
+    let mut moves = get_position_moves(engine, position, searchmoves);
     moves.truncate(engine.opt_variants);
+    moves
+}
 
-    let total_weight = match engine.opt_weightby {
-        WeightBy::Games => moves.iter().fold(0, |acc, x| acc + x.white + x.draws + x.black),
-        WeightBy::Score => moves.iter().fold(0, |acc, x| acc + (2*(if let Turn::White = engine.turn { x.white } else { x.black }) + x.draws)*100/(2*x.white + x.black + 2*x.draws)),
-        WeightBy::Random => u64::try_from(moves.len()).unwrap()
-    };
+fn choose_move(engine: &Engine, moves: Vec<&Move>, mut rng: impl Rng) -> Option<Move> {
+    let total_weight = moves.iter().fold(0, |acc, x| acc + get_move_weight(engine, x));
 
     if total_weight != 0 {
         let random = rng.gen_range(0, total_weight);
         let mut acc_weight = 0;
         for x in moves {
-            acc_weight += match engine.opt_weightby {
-                WeightBy::Games => x.white + x.draws + x.black,
-                WeightBy::Score => (2*(if let Turn::White = engine.turn { x.white } else { x.black }) + x.draws)*100/(2*x.white + x.black + 2*x.draws),
-                WeightBy::Random => 1
-            };
+            acc_weight += get_move_weight(engine, x);
 
             if random < acc_weight {
                 return Some(Move {
@@ -219,7 +579,8 @@ fn get_position_move(engine: &Engine, position: &PositionInfo, mut rng: impl ran
                     san: x.san.clone(),
                     white: x.white,
                     draws: x.draws,
-                    black: x.black
+                    black: x.black,
+                    average_rating: x.average_rating,
                 })
             }
         }
@@ -228,330 +589,752 @@ fn get_position_move(engine: &Engine, position: &PositionInfo, mut rng: impl ran
     None
 }
 
-fn process_uci<I, L>(mut stockfish_stdin: I, mut stockfish_lines: L) -> bool where
+fn print_options(engine: &Engine) {
+    for option in options::registry().iter().filter(|x| x.available(engine)) {
+        println!("{}", option.decl(engine));
+    }
+}
+
+fn process_uci<I, L>(engine: &Engine, mut stockfish_stdin: I, mut stockfish_lines: L) -> bool where
     I: io::Write,
     L: iter::Iterator<Item = String>
 {
+    if engine.book_only {
+        println!("{}", Response::IdName(format!("{} (book only)", ENGINE_NAME)));
+        print_options(engine);
+        println!("{}", UCIOK_RESP);
+        return true
+    }
+
     writeln!(stockfish_stdin, "{}", UCI_CMD).unwrap();
 
+    // Banners and output left over from before the handshake are dropped.
     loop {
         match stockfish_lines.next() {
-            Some(line) => {
-                if line.as_str().trim() == UCIOK_RESP {
-                    println!("{} {} LichessDB_Masters type check default false", OPTION_RESP, NAME_PARAM);
-                    println!("{} {} LichessDB_Bullet type check default true", OPTION_RESP, NAME_PARAM);
-                    println!("{} {} LichessDB_Blitz type check default true", OPTION_RESP, NAME_PARAM);
-                    println!("{} {} LichessDB_Rapid type check default true", OPTION_RESP, NAME_PARAM);
-                    println!("{} {} LichessDB_Classical type check default true", OPTION_RESP, NAME_PARAM);
-                    println!("{} {} LichessDB_Rating_1600_1800 type check default true", OPTION_RESP, NAME_PARAM);
-                    println!("{} {} LichessDB_Rating_1800_2000 type check default true", OPTION_RESP, NAME_PARAM);
-                    println!("{} {} LichessDB_Rating_2000_2200 type check default true", OPTION_RESP, NAME_PARAM);
-                    println!("{} {} LichessDB_Rating_2200_2500 type check default true", OPTION_RESP, NAME_PARAM);
-                    println!("{} {} LichessDB_Rating_Above_2500 type check default true", OPTION_RESP, NAME_PARAM);
-                    println!("{} {} LichessDB_Games_GT type spin default 30 min 1 max 1000000000", OPTION_RESP, NAME_PARAM);
-                    println!("{} {} LichessDB_Games_Percent_GT type spin default 1 min 0 max 100", OPTION_RESP, NAME_PARAM);
-                    println!("{} {} LichessDB_Score_GT type spin default 0 min 0 max 100", OPTION_RESP, NAME_PARAM);
-                    println!("{} {} LichessDB_Sort_By type combo default Games var Games var Score", OPTION_RESP, NAME_PARAM);
-                    println!("{} {} LichessDB_Variants type spin default 1 min 0 max 50", OPTION_RESP, NAME_PARAM);
-                    println!("{} {} LichessDB_Variant_Weight type combo default Random var Games var Score var Random", OPTION_RESP, NAME_PARAM);
+            Some(line) => match uci::parse_response(line.as_str()) {
+                Response::IdName(name) => println!("{}", Response::IdName(format!("{} over {}", ENGINE_NAME, name))),
+                Response::IdAuthor(_) | Response::Option(_) | Response::InfoString(_) | Response::CopyProtection(_) | Response::Registration(_) => println!("{}", line),
+                Response::UciOk => {
+                    print_options(engine);
                     println!("{}", UCIOK_RESP);
                     break
                 }
-                println!("{}", line);
-            }
+                _ => ()
+            },
             None => return false
         }
     }
     true
 }
 
-fn process_setoption<I>(engine: &mut Engine, args: Vec<&str>, mut stockfish_stdin: I) -> bool where
+// Options the wrapper does not know are passed on to the engine, if there is
+// one. Options the GUI was not shown are ignored.
+fn process_setoption<I>(engine: &mut Engine, name: &str, value: Option<&str>, mut stockfish_stdin: I) -> bool where
     I: io::Write,
 {
-    let mut arg = args.iter();
-    match arg.next() {
-        Some(&NAME_PARAM) => match arg.next() {
-            Some(&"LichessDB_Variant_Weight") => match arg.next() {
-                Some(&VALUE_PARAM) => match arg.next() {
-                    Some(&"Games") => engine.opt_weightby = WeightBy::Games, 
-                    Some(&"Score") => engine.opt_weightby = WeightBy::Score, 
-                    Some(&"Random") => engine.opt_weightby = WeightBy::Random, 
-                    _ => (),
+    match options::find(name) {
+        Some(option) if !option.available(engine) => (),
+        Some(option) => if let Err(err) = option.set(engine, value) {
+            println!("{} string {}", INFO_RESP, err);
+        },
+        None => {
+            if name.eq_ignore_ascii_case(MULTIPV_OPTION) {
+                if let Some(Ok(x)) = value.map(|x| x.parse::<usize>()) {
+                    engine.multipv = x.max(1);
                 }
-                _ => (),
             }
-            Some(&"LichessDB_Games_GT") => match arg.next() {
-                Some(&VALUE_PARAM) => match arg.next() {
-                    Some(x) => match x.parse().unwrap() {
-                        1...1000000000 => engine.opt_games_min = x.parse().unwrap(),
-                        _ => (),
-                    }
-                    None => (),
-                }
-                _ => (),
+            if engine.book_only {
+                return true
             }
-            Some(&"LichessDB_Games_Percent_GT") => match arg.next() {
-                Some(&VALUE_PARAM) => match arg.next() {
-                    Some(x) => match x.parse().unwrap() {
-                        1...100 => engine.opt_games_pct_min = x.parse().unwrap(),
-                        _ => (),
-                    }
-                    None => (),
+            writeln!(stockfish_stdin, "{}", Command::SetOption(name.to_string(), value.map(|x| x.to_string()))).unwrap();
+        }
+    }
+    true
+}
+
+fn process_isready<I, L>(engine: &Engine, mut stockfish_stdin: I, mut stockfish_lines: L) -> bool where
+    I: io::Write,
+    L: iter::Iterator<Item = String>
+{
+    if engine.book_only {
+        println!("{}", READYOK_RESP);
+        return true
+    }
+
+    writeln!(stockfish_stdin, "{}", ISREADY_CMD).unwrap();
+
+    loop {
+        match stockfish_lines.next() {
+            Some(line) => match uci::parse_response(line.as_str()) {
+                Response::ReadyOk => {
+                    println!("{}", READYOK_RESP);
+                    break
                 }
-                _ => (),
+                Response::InfoString(_) => println!("{}", line),
+                _ => ()
+            },
+            None => return false
+        }
+    }
+    true
+}
+
+fn set_board(engine: &mut Engine, board: Board) {
+    engine.fen = board.fen();
+    engine.turn = match board.turn() {
+        Color::White => Turn::White,
+        Color::Black => Turn::Black,
+    };
+    engine.board = board;
+}
+
+fn process_position<I>(engine: &mut Engine, position: Position, mut stockfish_stdin: I) -> bool where
+    I: io::Write,
+{
+    // The engine always gets the position, even one the book cannot follow.
+    writeln!(stockfish_stdin, "{}", position).unwrap();
+
+    let mut board = match &position.fen {
+        None => Board::startpos(),
+        Some(fen) => match Board::from_fen(fen.as_str()) {
+            Ok(board) => board,
+            Err(err) => {
+                println!("{} string {}", INFO_RESP, err);
+                engine.position_error = Some(err);
+                return true
             }
-            Some(&"LichessDB_Score_GT") => match arg.next() {
-                Some(&VALUE_PARAM) => match arg.next() {
-                    Some(x) => match x.parse().unwrap() {
-                        1...100 => engine.opt_score_pct_min = x.parse().unwrap(),
-                        _ => (),
-                    }
-                    None => (),
-                }
-                _ => (),
+        }
+    };
+
+    for uci in &position.moves {
+        if let Err(err) = board.play_uci(uci) {
+            println!("{} string {}", INFO_RESP, err);
+            engine.position_error = Some(err);
+            return true
+        }
+    }
+
+    // Going back to before the book was left means a different game.
+    if engine.book_exit_ply.is_some_and(|x| board.ply() < x) {
+        engine.book_exit_ply = None;
+    }
+    engine.position_error = None;
+    set_board(engine, board);
+
+    true
+}
+
+// The side to move's remaining time, increment and fixed move time, in
+// milliseconds.
+fn get_clock(engine: &Engine, go: &Go) -> (Option<u64>, u64, Option<u64>) {
+    let (time, inc) = match engine.turn {
+        Turn::White => (go.wtime, go.winc),
+        Turn::Black => (go.btime, go.binc),
+    };
+    (time.map(|x| x.max(0) as u64), inc.unwrap_or(0).max(0) as u64, go.movetime.map(|x| x.max(0) as u64))
+}
+
+// Without an engine there is nothing to time out.
+fn get_engine_timeout(engine: &Engine) -> Option<Duration> {
+    if engine.book_only {
+        return None
+    }
+    match engine.opt_engine_timeout {
+        0 => None,
+        x => Some(Duration::from_millis(x))
+    }
+}
+
+// Why the explorer is not worth asking for this go, if it is not.
+fn get_book_skip_reason(engine: &Engine, go: &Go) -> Option<String> {
+    if let Some(err) = &engine.position_error {
+        return Some(format!("position not understood ({})", err))
+    }
+    if engine.opt_book_exit == BookExit::Latch && engine.book_exit_ply.is_some() {
+        return Some("out of book".to_string())
+    }
+    let ply = engine.board.ply();
+    if engine.opt_max_ply > 0 && ply >= engine.opt_max_ply {
+        return Some(format!("ply {} past book limit {}", ply, engine.opt_max_ply))
+    }
+    match get_clock(engine, go) {
+        (Some(time), _, _) if time < engine.opt_min_time => Some(format!("{}ms left, below {}ms", time, engine.opt_min_time)),
+        _ => None
+    }
+}
+
+// Spend at most a fortieth of the remaining clock plus half the increment on
+// the explorer, or a quarter of a fixed move time.
+fn get_request_timeout(engine: &Engine, go: &Go) -> Duration {
+    let (time, inc, movetime) = get_clock(engine, go);
+    let budget = match (movetime, time) {
+        (Some(movetime), _) => movetime/4,
+        (None, Some(time)) => time/40 + inc/2,
+        (None, None) => engine.opt_request_timeout
+    };
+    Duration::from_millis(budget.min(engine.opt_request_timeout))
+}
+
+// Roughly what an engine spends on a move: an even share of the clock over
+// the moves to go plus most of the increment, or the fixed move time.
+fn get_move_budget(engine: &Engine, go: &Go) -> Option<u64> {
+    let (time, inc, movetime) = get_clock(engine, go);
+    match (movetime, time) {
+        (Some(movetime), _) => Some(movetime),
+        (None, Some(time)) => {
+            let moves = go.movestogo.map_or(DEFAULT_MOVES_TO_GO, u64::from);
+            Some((time/(moves + 1) + inc*3/4).min(time/2))
+        }
+        (None, None) => None
+    }
+}
+
+// The same search with the time already spent taken off our clock.
+fn get_remaining_go(engine: &Engine, go: &Go, elapsed: Duration) -> Go {
+    let elapsed = elapsed.as_millis() as i64;
+    let mut go = go.clone();
+    match engine.turn {
+        Turn::White => go.wtime = go.wtime.map(|x| (x - elapsed).max(1)),
+        Turn::Black => go.btime = go.btime.map(|x| (x - elapsed).max(1)),
+    }
+    go.movetime = go.movetime.map(|x| (x - elapsed).max(1));
+    go
+}
+
+// An engine that stays silent this long during a search is taken for hung:
+// the engine timeout, but no longer than the move budget on a clock.
+// Infinite and ponder searches may go quiet once they reach their depth.
+fn get_engine_silence(engine: &Engine, go: &Go) -> Option<Duration> {
+    if go.infinite || go.ponder {
+        return None
+    }
+    let timeout = get_engine_timeout(engine)?;
+    match get_move_budget(engine, go) {
+        Some(budget) => Some(timeout.min(Duration::from_millis(budget.max(MIN_ENGINE_SILENCE)))),
+        None => Some(timeout)
+    }
+}
+
+impl SearchInfo {
+    fn new() -> SearchInfo {
+        SearchInfo {
+            depth: 1,
+            seldepth: 1,
+            score: Score::Cp(0),
+            pv: vec![],
+        }
+    }
+
+    // Only the main line counts when the engine reports several.
+    fn update(&mut self, info: &uci::Info) {
+        if info.multipv.unwrap_or(1) == 1 {
+            self.apply(info);
+        }
+    }
+
+    fn apply(&mut self, info: &uci::Info) {
+        if let Some(depth) = info.depth {
+            self.depth = depth;
+        }
+        if let Some(seldepth) = info.seldepth {
+            self.seldepth = seldepth;
+        }
+        if let Some(score) = info.score {
+            self.score = score;
+        }
+        if !info.pv.is_empty() {
+            self.pv = info.pv.clone();
+        }
+    }
+
+    // Mate scores are mapped beyond any centipawn score, shorter mates first.
+    fn score_cp(&self) -> i64 {
+        match self.score {
+            Score::Mate(x) if x > 0 => MATE_SCORE - x,
+            Score::Mate(x) => -MATE_SCORE - x,
+            Score::Cp(x) => x
+        }
+    }
+
+    fn to_info(&self, multipv: Option<usize>) -> uci::Info {
+        uci::Info {
+            depth: Some(self.depth),
+            seldepth: Some(self.seldepth),
+            multipv,
+            score: Some(self.score),
+            pv: self.pv.clone(),
+            ..uci::Info::default()
+        }
+    }
+}
+
+// Runs a fixed-depth search, optionally restricted to one move and cut short
+// after movetime, and returns its final info together with the engine's
+// bestmove line.
+fn evaluate<I>(engine: &Engine, mut stockfish_stdin: I, queue: &mut EventQueue, searchmove: Option<&str>, movetime: Option<u64>) -> Option<(SearchInfo, String)> where
+    I: io::Write
+{
+    let go = Go {
+        depth: Some(engine.opt_eval_depth),
+        movetime: movetime.map(|x| x as i64),
+        searchmoves: searchmove.iter().map(|x| x.to_string()).collect(),
+        ..Go::default()
+    };
+    writeln!(stockfish_stdin, "{}", go).unwrap();
+    let mut info = SearchInfo::new();
+    loop {
+        let line = queue.engine_line(get_engine_timeout(engine))?;
+        match uci::parse_response(line.as_str()) {
+            Response::BestMove(_, _) => return Some((info, line)),
+            Response::Info(x) => info.update(&x),
+            _ => ()
+        }
+    }
+}
+
+// Keeps the candidates whose score is within LichessDB_Max_Eval_Drop of the
+// engine's best move at the same depth. The engine's own search is returned
+// for when no candidate survives.
+// Candidate searches are kept in evaluations so they can be reported later.
+// With a time budget, each search gets an even share of what is left of it.
+fn guard_candidates<'a, I>(engine: &Engine, candidates: Vec<&'a Move>, budget: Option<u64>, evaluations: &mut HashMap<String, SearchInfo>, mut stockfish_stdin: I, queue: &mut EventQueue) -> Option<(Vec<&'a Move>, SearchInfo, String)> where
+    I: io::Write
+{
+    let started = Instant::now();
+    let searches = candidates.len() + 1;
+    let share = |done: usize| budget.map(|x| (x.saturating_sub(started.elapsed().as_millis() as u64)/(searches - done) as u64).max(1));
+    let (best, bestmove_line) = evaluate(engine, &mut stockfish_stdin, queue, None, share(0))?;
+    let best_move = match uci::parse_response(bestmove_line.as_str()) {
+        Response::BestMove(x, _) => x,
+        _ => String::new()
+    };
+    let mut guarded = vec![];
+    for (i, x) in candidates.into_iter().enumerate() {
+        let uci = fix_castle(&x.uci);
+        let info = if uci == best_move {
+            best.clone()
+        } else {
+            evaluate(engine, &mut stockfish_stdin, queue, Some(uci.as_str()), share(i + 1))?.0
+        };
+        let score = info.score_cp();
+        evaluations.insert(uci, info);
+        if best.score_cp() - score <= engine.opt_max_eval_drop {
+            guarded.push(x);
+        } else {
+            println!("{} string book move {} rejected, score {} best {}", INFO_RESP, x.san, score, best.score_cp());
+        }
+    }
+    Some((guarded, best, bestmove_line))
+}
+
+// The score and PV must belong to the book moves, so those the engine or the
+// guard have not searched yet are searched together, one line each at the
+// engine's MultiPV. The search gets what is left of the move budget, and is
+// skipped with too little left. Returns false if the engine failed.
+fn search_book_moves<I>(engine: &Engine, moves: &[String], remaining: Option<u64>, evaluations: &mut HashMap<String, SearchInfo>, mut stockfish_stdin: I, queue: &mut EventQueue) -> bool where
+    I: io::Write
+{
+    let searchmoves: Vec<String> = moves.iter().filter(|x| !evaluations.contains_key(*x)).cloned().collect();
+    if searchmoves.is_empty() || remaining.is_some_and(|x| x < MIN_EVAL_TIME) {
+        return true
+    }
+    let go = Go {
+        depth: Some(engine.opt_eval_depth),
+        movetime: remaining.map(|x| x as i64),
+        searchmoves: searchmoves.clone(),
+        ..Go::default()
+    };
+    writeln!(stockfish_stdin, "{}", go).unwrap();
+    let mut lines: HashMap<String, SearchInfo> = HashMap::new();
+    loop {
+        let line = match queue.engine_line(get_engine_timeout(engine)) {
+            Some(line) => line,
+            None => return false
+        };
+        match uci::parse_response(line.as_str()) {
+            Response::BestMove(_, _) => break,
+            Response::Info(x) => if let Some(first) = x.pv.first() {
+                lines.entry(first.clone()).or_insert_with(SearchInfo::new).apply(&x);
+            },
+            _ => ()
+        }
+    }
+    for (uci, info) in lines {
+        if searchmoves.contains(&uci) {
+            evaluations.insert(uci, info);
+        }
+    }
+    true
+}
+
+fn print_book_move_info(info: &SearchInfo, x: &Move, multipv: usize) -> Vec<String> {
+    let pv = if info.pv.first() == Some(&x.uci) { info.pv.clone() } else { vec![x.uci.clone()] };
+    println!("{}", uci::Info { pv: pv.clone(), ..info.to_info(Some(multipv)) });
+    pv
+}
+
+// Without an engine there is nothing to fall back on outside the book.
+fn get_out_of_book_line(engine: &mut Engine) -> String {
+    let uci = match engine.opt_out_of_book {
+        OutOfBook::Resign => NO_MOVE.to_string(),
+        OutOfBook::Null => NULL_MOVE.to_string(),
+        // A board we could not set up has no moves to pick from.
+        OutOfBook::Random if engine.position_error.is_some() => NO_MOVE.to_string(),
+        OutOfBook::Random => {
+            let moves = engine.board.legal_moves();
+            match moves.len() {
+                0 => NO_MOVE.to_string(),
+                n => moves[engine.rng.gen_range(0, n)].uci()
             }
-            Some(&"LichessDB_Variants") => match arg.next() {
-                Some(&VALUE_PARAM) => match arg.next() {
-                    Some(x) => match x.parse().unwrap() {
-                        0...50 => engine.opt_variants = x.parse().unwrap(),
-                        _ => (),
+        }
+    };
+    Response::BestMove(uci, None).to_string()
+}
+
+// The explorer lookup runs while Stockfish searches. Engine output is held
+// back until the book answer arrives or the lookup deadline passes; book
+// candidates stop the search early unless the GUI controls when it ends.
+fn process_go<I>(engine: &mut Engine, go: Go, mut stockfish_stdin: I, queue: &mut EventQueue) -> bool where
+    I: io::Write
+{
+    writeln!(stockfish_stdin, "{}", go).unwrap();
+    engine.pending_go = Some((go.clone(), Instant::now()));
+
+    let timeout = get_request_timeout(engine, &go);
+    let book_deadline = Instant::now() + timeout;
+    let mut book: Option<Option<Arc<PositionInfo>>> = None;
+    match get_book_skip_reason(engine, &go) {
+        Some(reason) => {
+            if engine.debug {
+                println!("{} string {}, explorer skipped", INFO_RESP, reason);
+            }
+            // Also cancels a prefetch still running for the previous move.
+            engine.lookup_id.fetch_add(1, Ordering::SeqCst);
+            book = Some(None);
+        }
+        None => spawn_lookup(engine, timeout, queue.sender.clone())
+    }
+    let infinite = go.infinite;
+    let mut pondering = go.ponder;
+    let mut stop_sent = false;
+    let mut gui_stopped = false;
+    // Our clock only runs once pondering is over.
+    let mut started = Instant::now();
+    let budget = get_move_budget(engine, &go);
+
+    // The engine deadline moves on with every line the engine prints. A stop
+    // the engine does not answer within the engine timeout also counts as a hang.
+    let engine_timeout = get_engine_timeout(engine);
+    let mut silence = get_engine_silence(engine, &go);
+    let mut engine_deadline = silence.map(|x| Instant::now() + x);
+
+    let mut opening: Option<Opening> = None;
+    let mut held_lines = vec![];
+    let mut engine_bestmove: Option<String> = None;
+    if engine.book_only && !infinite && !pondering {
+        engine_bestmove = Some(get_out_of_book_line(engine));
+    }
+    let mut search = SearchInfo::new();
+
+    loop {
+        if let (Some(position), Some(line)) = (&book, &engine_bestmove) {
+            let mut bestmove_line = line.clone();
+            let mut evaluations = HashMap::new();
+            // LichessDB_Variants limits the moves to play; the GUI's MultiPV
+            // limits the book lines shown.
+            let moves = match position {
+                Some(position) => get_position_moves(engine, position, &go.searchmoves),
+                None => vec![]
+            };
+            let mut candidates: Vec<&Move> = moves.iter().take(engine.opt_variants).cloned().collect();
+            let mut rejected: Vec<String> = vec![];
+            if engine.opt_eval_guard && !engine.book_only && !candidates.is_empty() {
+                let remaining = budget.map(|x| x.saturating_sub(started.elapsed().as_millis() as u64));
+                let tried: Vec<String> = candidates.iter().map(|x| x.uci.clone()).collect();
+                match guard_candidates(engine, candidates, remaining, &mut evaluations, &mut stockfish_stdin, queue) {
+                    Some((guarded, best, guard_line)) => {
+                        rejected = tried.into_iter().filter(|x| !guarded.iter().any(|y| y.uci == *x)).collect();
+                        if guarded.is_empty() {
+                            // The search was cut short for the book, so it
+                            // goes on with whatever time is left.
+                            if stop_sent && !gui_stopped {
+                                let research = get_remaining_go(engine, &Go { ponder: false, ..go.clone() }, started.elapsed());
+                                writeln!(stockfish_stdin, "{}", research).unwrap();
+                                silence = get_engine_silence(engine, &research);
+                                engine_deadline = silence.map(|x| Instant::now() + x);
+                                engine.pending_go = Some((research, Instant::now()));
+                                book = Some(None);
+                                engine_bestmove = None;
+                                stop_sent = false;
+                                search = SearchInfo::new();
+                                continue
+                            }
+                            // A search that ran to the end is better than
+                            // the guard's; after a stop, the guard's is all there is.
+                            if gui_stopped {
+                                println!("{}", best.to_info(None));
+                                bestmove_line = guard_line;
+                            } else {
+                                println!("{}", search.to_info(None));
+                            }
+                        } else {
+                            bestmove_line = guard_line;
+                        }
+                        candidates = guarded;
                     }
-                    None => (),
+                    None => return false
                 }
-                _ => (),
             }
-            Some(&"LichessDB_Sort_By") => match arg.next() {
-                Some(&VALUE_PARAM) => match arg.next() {
-                    Some(&"Games") => engine.opt_sortby = SortBy::Games, 
-                    Some(&"Score") => engine.opt_sortby = SortBy::Score, 
-                    _ => (),
+            // The engine's own search covers the move it chose.
+            if let Response::BestMove(best, _) = uci::parse_response(line.as_str()) {
+                if !search.pv.is_empty() {
+                    evaluations.entry(best).or_insert_with(|| search.clone());
                 }
-                _ => (),
             }
-            Some(&"LichessDB_Masters") => match arg.next() {
-                Some(&VALUE_PARAM) => match arg.next() {
-                    Some(&"true") => engine.master_games = true, 
-                    Some(&"false") => engine.master_games = false, 
-                    _ => (),
-                }
-                _ => (),
+            if engine.debug {
+                let list = candidates.iter().map(|x| format!("{} {}", x.san, get_move_weight(engine, x))).collect::<Vec<String>>().join(", ");
+                println!("{} string book candidates: {}", INFO_RESP, if list.is_empty() { NONE_VALUE.to_string() } else { list });
             }
-            Some(&"LichessDB_Rating_1600_1800") => match arg.next() {
-                Some(&VALUE_PARAM) => match arg.next() {
-                    Some(&"true") => engine.ratings.rating_1600 = true, 
-                    Some(&"false") => engine.ratings.rating_1600 = false, 
-                    _ => (),
+            let mut rng = engine.rng.clone();
+            let chosen = choose_move(engine, candidates, &mut rng);
+            engine.rng = rng;
+            match chosen {
+                Some(x) => {
+                    let games = x.white + x.draws + x.black;
+                    if let Some(opening) = &opening {
+                        println!("{} string opening {} {}", INFO_RESP, opening.eco, opening.name);
+                    }
+                    match x.average_rating {
+                        Some(rating) => println!("{} white {} draws {} black {} games {} rating {} lichessdbmove {}", INFO_RESP, x.white*100/games.max(1), x.draws*100/games.max(1), x.black*100/games.max(1), games, rating, x.san),
+                        None => println!("{} white {} draws {} black {} games {} lichessdbmove {}", INFO_RESP, x.white*100/games.max(1), x.draws*100/games.max(1), x.black*100/games.max(1), games, x.san)
+                    }
+                    // The chosen move is the main line; the other book moves
+                    // the guard did not reject follow in book order.
+                    let others: Vec<Move> = moves.iter()
+                        .filter(|y| fix_castle(&y.uci) != x.uci && !rejected.contains(&y.uci))
+                        .take(engine.multipv - 1)
+                        .map(|y| Move { uci: fix_castle(&y.uci), ..Move::clone(y) })
+                        .collect();
+                    if !engine.book_only {
+                        let moves: Vec<String> = iter::once(&x).chain(&others).map(|y| y.uci.clone()).collect();
+                        let remaining = budget.map(|x| x.saturating_sub(started.elapsed().as_millis() as u64));
+                        if !search_book_moves(engine, &moves, remaining, &mut evaluations, &mut stockfish_stdin, queue) {
+                            return false
+                        }
+                    }
+                    let pv = match evaluations.remove(&x.uci) {
+                        Some(info) => print_book_move_info(&info, &x, 1),
+                        None => vec![x.uci.clone()]
+                    };
+                    for (i, other) in others.iter().enumerate() {
+                        let games = other.white + other.draws + other.black;
+                        println!("{} string multipv {} {} games {} score {}%", INFO_RESP, i + 2, other.san, games, get_move_score_pct(engine, other));
+                        if let Some(info) = evaluations.remove(&other.uci) {
+                            print_book_move_info(&info, other, i + 2);
+                        }
+                    }
+                    let ponder = get_book_reply(engine, &x).or_else(|| pv.get(1).cloned());
+                    println!("{}", Response::BestMove(x.uci.clone(), ponder));
+                    spawn_prefetch(engine, &x);
                 }
-                _ => (),
+                None => println!("{}", bestmove_line)
             }
-            Some(&"LichessDB_Rating_1800_2000") => match arg.next() {
-                Some(&VALUE_PARAM) => match arg.next() {
-                    Some(&"true") => engine.ratings.rating_1800 = true, 
-                    Some(&"false") => engine.ratings.rating_1800 = false, 
-                    _ => (),
+            engine.pending_go = None;
+            break
+        }
+
+        // The engine is not timed while we wait on the explorer ourselves.
+        let deadline = match book {
+            Some(_) => engine_deadline,
+            None => Some(book_deadline)
+        };
+        let event = match deadline {
+            Some(deadline) => match queue.receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(event) => event,
+                Err(mpsc::RecvTimeoutError::Timeout) if book.is_none() && Instant::now() >= book_deadline => {
+                    println!("{} string explorer lookup timed out", INFO_RESP);
+                    book = Some(None);
+                    engine_deadline = silence.map(|x| Instant::now() + x);
+                    held_lines.drain(..).for_each(|x: String| println!("{}", x));
+                    continue
                 }
-                _ => (),
-            }
-            Some(&"LichessDB_Rating_2000_2200") => match arg.next() {
-                Some(&VALUE_PARAM) => match arg.next() {
-                    Some(&"true") => engine.ratings.rating_2000 = true, 
-                    Some(&"false") => engine.ratings.rating_2000 = false, 
-                    _ => (),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    println!("{} string engine not responding", INFO_RESP);
+                    return false
                 }
-                _ => (),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return false
+            },
+            None => match queue.receiver.recv() {
+                Ok(event) => event,
+                Err(_) => return false
             }
-            Some(&"LichessDB_Rating_2200_2500") => match arg.next() {
-                Some(&VALUE_PARAM) => match arg.next() {
-                    Some(&"true") => engine.ratings.rating_2200 = true, 
-                    Some(&"false") => engine.ratings.rating_2200 = false, 
-                    _ => (),
+        };
+
+        match event {
+            Event::Book(id, position) if id == engine.lookup_id.load(Ordering::SeqCst) && book.is_none() => {
+                // Moves the GUI left out of searchmoves do not take the game out of book.
+                let left_book = position.as_ref().is_some_and(|x| get_position_candidates(engine, x, &[]).is_empty());
+                let position = position.filter(|x| !get_position_candidates(engine, x, &go.searchmoves).is_empty());
+                engine_deadline = silence.map(|x| Instant::now() + x);
+                match &position {
+                    Some(position) => {
+                        if engine.book_exit_ply.take().is_some() {
+                            println!("{} string back in book", INFO_RESP);
+                        }
+                        opening = position.opening.clone();
+                        held_lines.clear();
+                        if !infinite && !pondering && !stop_sent && engine_bestmove.is_none() {
+                            writeln!(stockfish_stdin, "{}", STOP_CMD).unwrap();
+                            stop_sent = true;
+                            silence = engine_timeout;
+                            engine_deadline = silence.map(|x| Instant::now() + x);
+                        }
+                    }
+                    None => {
+                        // Only a source that answered can tell the game has left book.
+                        if left_book && engine.opt_book_exit != BookExit::Off && engine.book_exit_ply.is_none() {
+                            println!("{} string out of book", INFO_RESP);
+                            engine.book_exit_ply = Some(engine.board.ply());
+                        }
+                        held_lines.drain(..).for_each(|x| println!("{}", x))
+                    }
                 }
-                _ => (),
+                book = Some(position);
             }
-            Some(&"LichessDB_Rating_Above_2500") => match arg.next() {
-                Some(&VALUE_PARAM) => match arg.next() {
-                    Some(&"true") => engine.ratings.rating_2500 = true, 
-                    Some(&"false") => engine.ratings.rating_2500 = false, 
-                    _ => (),
+            Event::Book(_, _) => (),
+            Event::EngineLine(line) => {
+                engine_deadline = silence.map(|x| Instant::now() + x);
+                match uci::parse_response(line.as_str()) {
+                    Response::BestMove(_, _) => {
+                        engine_bestmove = Some(line);
+                        continue
+                    }
+                    Response::Info(info) => search.update(&info),
+                    Response::InfoString(_) => (),
+                    _ => continue
                 }
-                _ => (),
-            }
-            Some(&"LichessDB_Bullet") => match arg.next() {
-                Some(&VALUE_PARAM) => match arg.next() {
-                    Some(&"true") => engine.tc.bullet = true, 
-                    Some(&"false") => engine.tc.bullet = false, 
-                    _ => (),
+                match book {
+                    Some(Some(_)) => (),
+                    Some(None) => println!("{}", line),
+                    None => held_lines.push(line)
                 }
-                _ => (),
             }
-            Some(&"LichessDB_Blitz") => match arg.next() {
-                Some(&VALUE_PARAM) => match arg.next() {
-                    Some(&"true") => engine.tc.blitz = true, 
-                    Some(&"false") => engine.tc.blitz = false, 
-                    _ => (),
-                }
-                _ => (),
+            Event::EngineExit => {
+                queue.engine_exited = true;
+                return false
             }
-            Some(&"LichessDB_Rapid") => match arg.next() {
-                Some(&VALUE_PARAM) => match arg.next() {
-                    Some(&"true") => engine.tc.rapid = true, 
-                    Some(&"false") => engine.tc.rapid = false, 
-                    _ => (),
+            Event::Input(line) => match uci::parse_command(line.as_str()) {
+                Command::Stop => {
+                    gui_stopped = true;
+                    // The GUI wants a move now, so the book is not waited for any longer.
+                    if book.is_none() {
+                        book = Some(None);
+                        engine_deadline = silence.map(|x| Instant::now() + x);
+                        held_lines.drain(..).for_each(|x| println!("{}", x));
+                    }
+                    if engine.book_only && engine_bestmove.is_none() {
+                        engine_bestmove = Some(get_out_of_book_line(engine));
+                    }
+                    if !stop_sent && engine_bestmove.is_none() {
+                        writeln!(stockfish_stdin, "{}", STOP_CMD).unwrap();
+                        stop_sent = true;
+                        silence = engine_timeout;
+                        engine_deadline = silence.map(|x| Instant::now() + x);
+                    }
+                    // Should the engine have to be restarted, the GUI still wants a move right away.
+                    engine.pending_go = Some((Go { depth: Some(1), ..Go::default() }, Instant::now()));
                 }
-                _ => (),
-            }
-            Some(&"LichessDB_Classical") => match arg.next() {
-                Some(&VALUE_PARAM) => match arg.next() {
-                    Some(&"true") => engine.tc.classical = true, 
-                    Some(&"false") => engine.tc.classical = false, 
-                    _ => (),
+                Command::PonderHit => {
+                    writeln!(stockfish_stdin, "{}", PONDERHIT_CMD).unwrap();
+                    pondering = false;
+                    started = Instant::now();
+                    engine.pending_go = Some((Go { ponder: false, ..go.clone() }, started));
+                    silence = get_engine_silence(engine, &Go { ponder: false, ..go.clone() });
+                    engine_deadline = silence.map(|x| Instant::now() + x);
+                    if engine.book_only && !infinite && engine_bestmove.is_none() {
+                        engine_bestmove = Some(get_out_of_book_line(engine));
+                    }
+                    if let Some(Some(_)) = book {
+                        if !infinite && !stop_sent && engine_bestmove.is_none() {
+                            writeln!(stockfish_stdin, "{}", STOP_CMD).unwrap();
+                            stop_sent = true;
+                            silence = engine_timeout;
+                            engine_deadline = silence.map(|x| Instant::now() + x);
+                        }
+                    }
                 }
-                _ => (),
-            }
-            Some(_) => {
-                let command_line = args.iter().fold(SETOPTION_CMD.to_string(), |acc, x| acc + " " + x);
-                writeln!(stockfish_stdin, "{}", command_line).unwrap();
-            }
-            None => println!("No such option: "),
+                Command::IsReady => println!("{}", READYOK_RESP),
+                Command::Quit => {
+                    if engine_bestmove.is_none() {
+                        writeln!(stockfish_stdin, "{}", STOP_CMD).unwrap();
+                    }
+                    queue.input.push_front(line);
+                    return true
+                }
+                _ => queue.input.push_back(line)
+            },
+            Event::InputExit => queue.input.push_back(QUIT_CMD.to_string())
         }
-        _ => println!("No such option: "),
     }
     true
 }
 
-fn process_isready<I, L>(mut stockfish_stdin: I, mut stockfish_lines: L) -> bool where
+fn process_stop<I>(mut stockfish_stdin: I) -> bool where
     I: io::Write,
-    L: iter::Iterator<Item = String>
 {
-    writeln!(stockfish_stdin, "{}", ISREADY_CMD).unwrap();
+    writeln!(stockfish_stdin, "{}", STOP_CMD).unwrap();
 
-    match stockfish_lines.next() {
-        Some(line) => {
-            if line.as_str().trim() == READYOK_RESP {
-                println!("{}", line);
-            } else {
-                return false
-            }
-        }
-        None => return false
-    }
     true
 }
 
-fn process_position<I>(engine: &mut Engine, args: Vec<&str>, mut stockfish_stdin: I) -> bool where
+// A non-zero seed makes the book choices of every game reproducible.
+fn reset_rng(engine: &mut Engine) {
+    engine.rng = match engine.opt_seed {
+        0 => StdRng::from_entropy(),
+        seed => StdRng::seed_from_u64(seed)
+    };
+}
+
+fn process_ucinewgame<I>(engine: &mut Engine, mut stockfish_stdin: I) -> bool where
     I: io::Write,
 {
-    if args.is_empty() {
-        return true
-    } else if args[0] == "startpos" {
-        engine.fen = STARTPOS.to_string();
-        engine.turn = Turn::White;
-    } else if args[0] == "fen" {
-        engine.turn = match args[2] {
-            "w" => Turn::White,
-            "b" => Turn::Black,
-            _ => return true
-        };
-
-        let mut iter = args.iter();
-        iter.next().unwrap();
-        engine.fen = iter.fold(String::new(), |acc, x| acc + x + " ");
-    }
-
-    let command_line = args.iter().fold(POSITION_CMD.to_string(), |acc, x| acc + " " + x);
-    writeln!(stockfish_stdin, "{}", command_line).unwrap();
+    writeln!(stockfish_stdin, "{}", UCINEWGAME_CMD).unwrap();
+    set_board(engine, Board::startpos());
+    reset_rng(engine);
+    engine.book_exit_ply = None;
 
     true
 }
 
-fn process_go<I, L>(engine: &mut Engine, args: Vec<&str>, mut stockfish_stdin: I, mut stockfish_lines: L) -> bool where
+fn process_debug<I>(engine: &mut Engine, on: bool, mut stockfish_stdin: I) -> bool where
     I: io::Write,
-    L: iter::Iterator<Item = String>
 {
-    let command_line = args.iter().fold(GO_CMD.to_string(), |acc, x| acc + " " + x);
-    writeln!(stockfish_stdin, "{}", command_line).unwrap();
+    engine.debug = on;
+    writeln!(stockfish_stdin, "{}", Command::Debug(on)).unwrap();
 
-    let rng = rand::thread_rng();
-    let position = get_position_info_cached(engine.fen.as_str(), engine.master_games, &engine.ratings, &engine.tc, &mut engine.cache).unwrap();
-    let best_move = get_position_move(&engine, position.borrow(), rng);
+    true
+}
 
-    let mut depth = "1".to_string();
-    let mut seldepth = "1".to_string();
-    let mut score_unit = "cp".to_string();
-    let mut score = "0".to_string();
+fn process_register<I>(args: String, mut stockfish_stdin: I) -> bool where
+    I: io::Write,
+{
+    writeln!(stockfish_stdin, "{}", Command::Register(args)).unwrap();
 
-    loop {
-        match stockfish_lines.next() {
-            Some(line) => {
-                if line.as_str().starts_with(BESTMOVE_RESP) {
-                    match best_move {
-                        Some(x) => {
-                            let games = x.white + x.draws + x.black;
-                            println!("{} white {} draws {} black {} games {} lichessdbmove {}", INFO_RESP, x.white*100/games, x.draws*100/games, x.black*100/games, games, x.san);
-                            println!("{} depth {} seldepth {} multipv 1 score {} {} pv {}", INFO_RESP, depth, seldepth, score_unit, score, x.uci);
-                            println!("{} {}", BESTMOVE_RESP, x.uci);
-                        }
-                        None => println!("{}", line)
-                    }
-                    break
-                } else {
-                    match best_move {
-                        Some(_) => {
-                            let mut words = line.as_str().split_ascii_whitespace();
-                            match words.next() {
-                                Some(INFO_RESP) => loop {
-                                    match words.next() {
-                                        Some(word) => match word {
-                                            "depth" => match words.next() {
-                                                Some(word) => depth = word.to_string(),
-                                                None => break
-                                            },
-                                            "seldepth" => match words.next() {
-                                                Some(word) => seldepth = word.to_string(),
-                                                None => break
-                                            },
-                                            "score" => {
-                                                match words.next() {
-                                                    Some("cp") => score_unit = "cp".to_string(),
-                                                    Some("mate") => score_unit = "mate".to_string(),
-                                                    Some(_) => (),
-                                                    None => break
-                                                }
-                                                match words.next() {
-                                                    Some(word) => score = word.to_string(),
-                                                    None => break
-                                                }
-                                            },
-                                            _ => ()
-                                        }
-                                        None => break
-                                    }
-                                }
-                                Some(_) => (),
-                                None => break
-                            }
-                        }
-                        None => println!("{}", line)
-                    }
-                }
-            }
-            None => return false 
-        }
-    }
     true
 }
 
-fn process_stop<I>(mut stockfish_stdin: I) -> bool where
+fn process_ponderhit<I>(mut stockfish_stdin: I) -> bool where
     I: io::Write,
 {
-    writeln!(stockfish_stdin, "{}", STOP_CMD).unwrap();
+    writeln!(stockfish_stdin, "{}", PONDERHIT_CMD).unwrap();
 
     true
 }
 
-fn main() -> io::Result<()> 
-{
-    let mut engine = Engine {
+fn default_engine() -> Engine {
+    Engine {
+        source: SourceKind::Lichess,
+        fallback_sources: vec![],
         master_games: false,
 
         ratings: RatingFilter {
@@ -575,56 +1358,525 @@ fn main() -> io::Result<()>
         opt_sortby: SortBy::Games,
         opt_variants: 1,
         opt_weightby: WeightBy::Random,
+        opt_network_backoff: DEFAULT_NETWORK_BACKOFF,
+        opt_request_timeout: source::DEFAULT_REQUEST_TIMEOUT_MS,
+        opt_prefetch: DEFAULT_PREFETCH,
+        opt_eval_guard: false,
+        opt_max_eval_drop: DEFAULT_MAX_EVAL_DROP,
+        opt_eval_depth: DEFAULT_EVAL_DEPTH,
+        opt_book_exit: BookExit::Off,
+        opt_max_ply: 0,
+        opt_min_time: 0,
+        opt_out_of_book: OutOfBook::Resign,
+        opt_engine_timeout: DEFAULT_ENGINE_TIMEOUT,
+        book_only: false,
 
+        board: Board::startpos(),
         fen: STARTPOS.to_string(),
         turn: Turn::White,
-        cache: HashMap::new(),
+        multipv: 1,
+        debug: false,
+        opt_seed: 0,
+        rng: StdRng::from_entropy(),
+        book_exit_ply: None,
+        position_error: None,
+        pending_go: None,
+        explorer: Arc::new(Mutex::new(Explorer {
+            cache: PositionCache::new(cache::default_cache_path(), cache::DEFAULT_CACHE_SIZE, cache::DEFAULT_CACHE_TTL_HOURS),
+            sources: Sources::new(),
+            offline_until: None,
+            live_lookups: 0,
+        })),
+        lookup_id: Arc::new(AtomicU64::new(0)),
+    }
+}
+
+// An explicitly requested config file must exist; the default one is optional.
+fn load_config(args: &mut Vec<String>) -> io::Result<Config> {
+    let explicit = match args.iter().position(|x| x == CONFIG_FLAG) {
+        Some(i) => {
+            if i + 1 >= args.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} expects a path", CONFIG_FLAG)))
+            }
+            let path = args.remove(i + 1);
+            args.remove(i);
+            Some(PathBuf::from(path))
+        }
+        None => env::var_os(CONFIG_ENV).map(PathBuf::from)
     };
+    match explicit {
+        Some(path) => load_config_file(&path),
+        None => match config::default_config_path() {
+            Some(ref path) if path.exists() => load_config_file(path),
+            _ => Ok(Config::default())
+        }
+    }
+}
 
-    let mut stockfish = process::Command::new("stockfish")
-        .stdin(process::Stdio::piped())
-        .stdout(process::Stdio::piped())
-        .stderr(process::Stdio::inherit())
-        .spawn().unwrap();
-
-    let mut stockfish_stdin = stockfish.stdin.as_mut().unwrap();
-    let mut stockfish_lines = io::BufReader::new(stockfish.stdout.as_mut().unwrap()).lines().map(|l| l.unwrap());
-    match stockfish_lines.next() {
-        Some(line) => println!("Lichessdbfish over {}", line),
-        None => ()
-    }
-
-    let mut lines = io::BufReader::new(io::stdin()).lines().map(|l| l.unwrap());
-    while match lines.next() {
-        Some(line) => {
-            let mut words = line.as_str().split_ascii_whitespace();
-            match words.next() {
-                Some(word) => {
-                    match word {
-                        UCI_CMD       => process_uci(&mut stockfish_stdin, &mut stockfish_lines),
-                        SETOPTION_CMD => process_setoption(&mut engine, words.collect(), &mut stockfish_stdin),
-                        ISREADY_CMD   => process_isready(&mut stockfish_stdin, &mut stockfish_lines),
-                        POSITION_CMD  => process_position(&mut engine, words.collect(), &mut stockfish_stdin),
-                        GO_CMD        => process_go(&mut engine, words.collect(), &mut stockfish_stdin, &mut stockfish_lines),
-                        STOP_CMD      => process_stop(&mut stockfish_stdin),
-                        QUIT_CMD      => false,
-                        unknown_cmd   => {
-                            println!("Unknown command: {}", unknown_cmd);
-                            true
-                        }
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    match args.iter().position(|x| x == flag) {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false
+    }
+}
+
+fn take_flag_values(args: &mut Vec<String>, flag: &str) -> io::Result<Vec<String>> {
+    let mut values = vec![];
+    while let Some(i) = args.iter().position(|x| x == flag) {
+        if i + 1 >= args.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} expects a value", flag)))
+        }
+        values.push(args.remove(i + 1));
+        args.remove(i);
+    }
+    Ok(values)
+}
+
+fn get_engine_command(args: &mut Vec<String>, config: &Config) -> io::Result<EngineCommand> {
+    let path = take_flag_values(args, ENGINE_FLAG)?.pop()
+        .or_else(|| env::var(ENGINE_ENV).ok())
+        .or_else(|| config.engine.path.clone())
+        .unwrap_or_else(|| DEFAULT_ENGINE.to_string());
+
+    let flag_args = take_flag_values(args, ENGINE_ARG_FLAG)?;
+    let engine_args = if !flag_args.is_empty() {
+        flag_args
+    } else {
+        match env::var(ENGINE_ARGS_ENV) {
+            Ok(x) => x.split_ascii_whitespace().map(|x| x.to_string()).collect(),
+            Err(_) => config.engine.args.clone()
+        }
+    };
+
+    let dir = take_flag_values(args, ENGINE_DIR_FLAG)?.pop()
+        .or_else(|| env::var(ENGINE_DIR_ENV).ok())
+        .or_else(|| config.engine.dir.clone())
+        .map(PathBuf::from);
+
+    let mut engine_env: Vec<(String, String)> = config.engine.env.iter().map(|(name, value)| (name.clone(), value.clone())).collect();
+    for x in take_flag_values(args, ENGINE_ENV_FLAG)? {
+        let mut parts = x.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) if !name.is_empty() => engine_env.push((name.to_string(), value.to_string())),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} expects NAME=VALUE", ENGINE_ENV_FLAG)))
+        }
+    }
+
+    Ok(EngineCommand { path, args: engine_args, dir, env: engine_env })
+}
+
+fn load_config_file(path: &Path) -> io::Result<Config> {
+    match Config::load(path) {
+        Ok(config) => Ok(config),
+        Err(err) => Err(io::Error::new(err.kind(), format!("Cannot read config {}: {}", path.display(), err)))
+    }
+}
+
+fn apply_config<I>(engine: &mut Engine, config: &Config, mut stockfish_stdin: I) where
+    I: Write
+{
+    for (name, value) in config.options() {
+        process_setoption(engine, name.as_str(), Some(value.as_str()), &mut stockfish_stdin);
+    }
+}
+
+fn run_import_pgn(args: &[String]) -> io::Result<()> {
+    let mut max_ply = index::DEFAULT_MAX_PLY;
+    let mut max_positions = index::DEFAULT_MAX_POSITIONS;
+    let mut paths = vec![];
+    let mut arg = args.iter();
+    while let Some(x) = arg.next() {
+        match x.as_str() {
+            MAX_PLY_FLAG => match arg.next().map(|x| x.parse()) {
+                Some(Ok(x)) => max_ply = x,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} expects a number", MAX_PLY_FLAG)))
+            },
+            MAX_POSITIONS_FLAG => match arg.next().map(|x| x.parse()) {
+                Some(Ok(x)) if x > 0 => max_positions = x,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} expects a positive number", MAX_POSITIONS_FLAG)))
+            },
+            path => paths.push(path)
+        }
+    }
+    if paths.len() < 2 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Usage: lichessdbfish {} [{} N] [{} N] <index file> <pgn file|{}>...", IMPORT_PGN_SUBCMD, MAX_PLY_FLAG, MAX_POSITIONS_FLAG, STDIN_PATH)))
+    }
+
+    // New games are merged into an existing index file.
+    let index_path = PathBuf::from(paths[0]);
+    let mut index = LocalIndex::new(&index_path, max_positions);
+
+    for pgn_path in &paths[1..] {
+        let (imported, skipped) = if *pgn_path == STDIN_PATH {
+            index.import(io::BufReader::new(io::stdin()), max_ply)?
+        } else {
+            index.import(io::BufReader::new(fs::File::open(pgn_path)?), max_ply)?
+        };
+        eprintln!("{}: imported {} games, skipped {}", pgn_path, imported, skipped);
+    }
+
+    let positions = index.flush()?;
+    eprintln!("{}: {} positions", index_path.display(), positions);
+    Ok(())
+}
+
+// Waits out a rate limit instead of leaving a hole in the book.
+fn get_export_source_position_info(engine: &Engine, lookup: &Lookup, kind: SourceKind) -> Result<Arc<PositionInfo>, ExplorerError> {
+    let mut retries = 0;
+    loop {
+        let err = match get_position_info_cached(&engine.explorer, lookup, kind) {
+            Ok(position) => return Ok(position),
+            Err(err) => err
+        };
+        let until = match (&err, lock_explorer(engine).offline_until) {
+            (ExplorerError::RateLimited(_), _) if retries >= MAX_EXPORT_RETRIES => return Err(err),
+            (ExplorerError::RateLimited(_), Some(until)) => until,
+            (ExplorerError::RateLimited(_), None) => Instant::now() + Duration::from_secs(DEFAULT_NETWORK_BACKOFF),
+            _ => return Err(err)
+        };
+        retries += 1;
+        let wait = until.saturating_duration_since(Instant::now());
+        eprintln!("{}: {}, retrying in {}s", engine.fen, err, wait.as_secs() + 1);
+        thread::sleep(wait);
+    }
+}
+
+fn get_export_position_info(engine: &Engine) -> Result<Arc<PositionInfo>, String> {
+    let lookup = get_lookup(engine, Duration::from_millis(engine.opt_request_timeout));
+    let mut result = Err(format!("{}: no explorer source", engine.fen));
+    for kind in iter::once(lookup.primary).chain(lookup.fallbacks.iter().cloned()) {
+        match get_export_source_position_info(engine, &lookup, kind) {
+            Ok(position) => {
+                let found = !position.moves.is_empty();
+                result = Ok(position);
+                if found {
+                    break
+                }
+            }
+            Err(err) => result = Err(format!("{}: {} explorer: {}", engine.fen, kind.name(), err))
+        }
+    }
+    result
+}
+
+// LichessDB_Variants only limits our own moves; every opponent reply that
+// passes the thresholds is followed.
+fn export_polyglot_tree(engine: &mut Engine, board: Board, color: Color, depth: usize, visited: &mut HashMap<u64, usize>, entries: &mut Vec<PolyglotEntry>) -> Result<(), String> {
+    let key = polyglot::hash(&board);
+    let first_visit = match visited.get(&key) {
+        Some(explored) if *explored >= depth => return Ok(()),
+        Some(_) => false,
+        None => true
+    };
+    visited.insert(key, depth);
+
+    set_board(engine, board.clone());
+    let position = get_export_position_info(engine)?;
+    let moves = if board.turn() == color {
+        get_position_candidates(engine, &position, &[])
+    } else {
+        get_position_moves(engine, &position, &[])
+    };
+    let candidates: Vec<(ChessMove, u64)> = moves.iter()
+        .filter_map(|x| ChessMove::from_uci(fix_castle(&x.uci).as_str()).ok().map(|chess_move| (chess_move, get_move_weight(engine, x))))
+        .collect();
+    let max_weight = candidates.iter().map(|(_, weight)| *weight).max().unwrap_or(0);
+
+    for (chess_move, weight) in candidates {
+        if first_visit {
+            entries.push(PolyglotEntry {
+                key,
+                raw_move: polyglot::encode_move(&board, chess_move),
+                weight: u16::try_from(weight*u64::from(u16::MAX)/max_weight.max(1)).unwrap_or(u16::MAX).max(1),
+            });
+        }
+        if depth > 1 {
+            let mut next = board.clone();
+            if next.play(chess_move).is_ok() {
+                export_polyglot_tree(engine, next, color, depth - 1, visited, entries)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_export_polyglot(args: &[String], config: &Config) -> io::Result<()> {
+    let mut engine = default_engine();
+    apply_config(&mut engine, config, io::sink());
+    let mut root = Board::startpos();
+    let mut color = None;
+    let mut depth = DEFAULT_EXPORT_DEPTH;
+    let mut out_path = None;
+    let mut arg = args.iter();
+    while let Some(x) = arg.next() {
+        match x.as_str() {
+            FEN_FLAG => match arg.next().map(|x| Board::from_fen(x)) {
+                Some(Ok(board)) => root = board,
+                Some(Err(err)) => return Err(io::Error::new(io::ErrorKind::InvalidInput, err)),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} expects a FEN", FEN_FLAG)))
+            },
+            COLOR_FLAG => match arg.next().map(|x| x.as_str()) {
+                Some("white") => color = Some(Color::White),
+                Some("black") => color = Some(Color::Black),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} expects white or black", COLOR_FLAG)))
+            },
+            DEPTH_FLAG => match arg.next().map(|x| x.parse()) {
+                Some(Ok(x)) => depth = x,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} expects a number", DEPTH_FLAG)))
+            },
+            SET_FLAG => match (arg.next(), arg.next()) {
+                (Some(name), Some(value)) => {
+                    process_setoption(&mut engine, name.as_str(), Some(value.as_str()), io::sink());
+                }
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} expects an option name and value", SET_FLAG)))
+            },
+            path => out_path = Some(PathBuf::from(path))
+        }
+    }
+    let out_path = match out_path {
+        Some(path) => path,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Usage: lichessdbfish {} [{} FEN] [{} white|black] [{} N] [{} NAME VALUE]... <book.bin>", EXPORT_POLYGLOT_SUBCMD, FEN_FLAG, COLOR_FLAG, DEPTH_FLAG, SET_FLAG)))
+    };
+
+    let mut visited = HashMap::new();
+    let mut entries = vec![];
+    if depth > 0 {
+        // The book plays for the side to move unless told otherwise.
+        let color = color.unwrap_or_else(|| root.turn());
+        if let Err(err) = export_polyglot_tree(&mut engine, root, color, depth, &mut visited, &mut entries) {
+            return Err(io::Error::other(err))
+        }
+    }
+
+    polyglot::write_book(&out_path, &mut entries)?;
+    eprintln!("{}: {} positions, {} moves", out_path.display(), visited.len(), entries.len());
+    Ok(())
+}
+
+fn wait_for_engine(queue: &mut EventQueue, timeout: Option<Duration>, response: Response) -> bool {
+    loop {
+        match queue.engine_line(timeout) {
+            Some(line) => if uci::parse_response(line.as_str()) == response { return true },
+            None => return false
+        }
+    }
+}
+
+// A new engine gets the handshake, options and position the old one had.
+fn restart_engine(engine: &Engine, supervisor: &mut Supervisor, queue: &mut EventQueue, restarts: &mut u32) -> bool {
+    let timeout = get_engine_timeout(engine);
+    while *restarts < MAX_ENGINE_RESTARTS {
+        *restarts += 1;
+        println!("{} string restarting engine", INFO_RESP);
+        supervisor.stop();
+        queue.discard_engine_events();
+        if let Err(err) = supervisor.start() {
+            println!("{} string {}", INFO_RESP, err);
+            continue
+        }
+        writeln!(supervisor, "{}", UCI_CMD).unwrap();
+        if !wait_for_engine(queue, timeout, Response::UciOk) {
+            continue
+        }
+        for line in supervisor.replay() {
+            writeln!(supervisor, "{}", line).unwrap();
+        }
+        writeln!(supervisor, "{}", ISREADY_CMD).unwrap();
+        if wait_for_engine(queue, timeout, Response::ReadyOk) {
+            return true
+        }
+    }
+    println!("{} string engine failed {} times in a row, giving up", INFO_RESP, MAX_ENGINE_RESTARTS);
+    false
+}
+
+fn main() -> io::Result<()> 
+{
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let config = load_config(&mut args)?;
+    let book_only = take_flag(&mut args, BOOK_ONLY_FLAG) || config.engine.book_only;
+    let engine_command = get_engine_command(&mut args, &config)?;
+    match args.first().map(|x| x.as_str()) {
+        Some(IMPORT_PGN_SUBCMD) => return run_import_pgn(&args[1..]),
+        Some(EXPORT_POLYGLOT_SUBCMD) => return run_export_polyglot(&args[1..], &config),
+        _ => ()
+    }
+
+    let mut engine = default_engine();
+    engine.book_only = book_only;
+
+    if book_only {
+        println!("{} (book only)", ENGINE_NAME);
+    } else {
+        println!("{} over {}", ENGINE_NAME, engine_command.path);
+    }
+
+    let mut queue = EventQueue::new();
+    // In book-only mode no engine is started and whatever would go to it is dropped.
+    let mut supervisor = Supervisor::new(engine_command, queue.sender.clone());
+    if !book_only {
+        supervisor.start()?;
+    }
+    let input_events = queue.sender.clone();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(line) => if input_events.send(Event::Input(line)).is_err() { return },
+                Err(_) => break
+            }
+        }
+        let _ = input_events.send(Event::InputExit);
+    });
+
+    apply_config(&mut engine, &config, &mut supervisor);
+
+    let mut restarts = 0;
+    while let Some(line) = queue.next_input() {
+        let done = !queue.engine_exited && {
+            let engine_timeout = get_engine_timeout(&engine);
+            match uci::parse_command(line.as_str()) {
+                Command::Uci                    => process_uci(&engine, &mut supervisor, iter::from_fn(|| queue.engine_line(engine_timeout))),
+                Command::SetOption(name, value) => process_setoption(&mut engine, name.as_str(), value.as_deref(), &mut supervisor),
+                Command::IsReady                => process_isready(&engine, &mut supervisor, iter::from_fn(|| queue.engine_line(engine_timeout))),
+                Command::Position(position)     => process_position(&mut engine, position, &mut supervisor),
+                Command::Go(go)                 => process_go(&mut engine, go, &mut supervisor, &mut queue),
+                Command::Stop                   => process_stop(&mut supervisor),
+                Command::PonderHit              => process_ponderhit(&mut supervisor),
+                Command::UciNewGame             => process_ucinewgame(&mut engine, &mut supervisor),
+                Command::Debug(on)              => process_debug(&mut engine, on, &mut supervisor),
+                Command::Register(args)         => process_register(args, &mut supervisor),
+                Command::Quit                   => break,
+                Command::Unknown(unknown)       => {
+                    if !unknown.is_empty() {
+                        println!("Unknown command: {}", unknown);
                     }
+                    true
                 }
-                None => true
             }
+        };
+        if done {
+            restarts = 0;
+            continue
         }
-        None => false
-    }{}
 
-    writeln!(stockfish_stdin, "{}", QUIT_CMD).unwrap();
-    writeln!(stockfish_stdin, "{}", EXIT_CMD).unwrap();
-    writeln!(stockfish_stdin, "{}", QUIT_CMD).unwrap();
+        // The engine died or hung: start a new one and try again, resuming
+        // an interrupted search where it was. Book-only mode has none to restart.
+        if engine.book_only || !restart_engine(&engine, &mut supervisor, &mut queue, &mut restarts) {
+            break
+        }
+        match engine.pending_go.take() {
+            // Our clock has been running since the search started, unless it was pondering.
+            Some((go, _)) if go.ponder => queue.input.push_front(go.to_string()),
+            Some((go, started)) => queue.input.push_front(get_remaining_go(&engine, &go, started.elapsed()).to_string()),
+            None => queue.input.push_front(line)
+        }
+    }
+
+    writeln!(supervisor, "{}", QUIT_CMD).unwrap();
+    writeln!(supervisor, "{}", EXIT_CMD).unwrap();
+    writeln!(supervisor, "{}", QUIT_CMD).unwrap();
 
-    stockfish.wait().unwrap();
+    supervisor.wait();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn book_entry(board: &Board, uci: &str, weight: u16) -> PolyglotEntry {
+        PolyglotEntry { key: polyglot::hash(board), raw_move: polyglot::encode_move(board, ChessMove::from_uci(uci).unwrap()), weight }
+    }
+
+    #[test]
+    fn zero_weight_book_moves_are_not_played() {
+        let path = env::temp_dir().join(format!("lichessdbfish-test-{}-zero.bin", process::id()));
+        let mut engine = default_engine();
+        engine.opt_games_pct_min = 0;
+        engine.opt_weightby = WeightBy::Score;
+        let board = Board::startpos();
+
+        polyglot::write_book(&path, &mut [book_entry(&board, "e2e4", 0), book_entry(&board, "d2d4", 0)]).unwrap();
+        let position = polyglot::PolyglotBook::load(&path).unwrap().position_info(&board);
+        assert!(get_position_moves(&engine, &position, &[]).is_empty());
+
+        polyglot::write_book(&path, &mut [book_entry(&board, "e2e4", 0), book_entry(&board, "d2d4", 3)]).unwrap();
+        let position = polyglot::PolyglotBook::load(&path).unwrap().position_info(&board);
+        let moves = get_position_moves(&engine, &position, &[]);
+        assert_eq!(moves.iter().map(|x| x.uci.as_str()).collect::<Vec<&str>>(), vec!["d2d4"]);
+        let _ = fs::remove_file(&path);
+
+        // Moves without games from any other source do not divide by zero either.
+        let empty = Move { uci: "e2e4".to_string(), san: "e4".to_string(), white: 0, draws: 0, black: 0, average_rating: None };
+        let position = PositionInfo { white: 0, draws: 0, black: 0, moves: vec![empty], opening: None, book_weights: true };
+        engine.opt_sortby = SortBy::Score;
+        assert_eq!(get_position_moves(&engine, &position, &[]).len(), 1);
+        assert_eq!(get_move_weight(&engine, &position.moves[0]), 0);
+    }
+
+    #[test]
+    fn fallbacks_are_tried_in_order() {
+        let index_path = env::temp_dir().join(format!("lichessdbfish-test-{}-chain.jsonl", process::id()));
+        let book_path = env::temp_dir().join(format!("lichessdbfish-test-{}-chain.bin", process::id()));
+        let _ = fs::remove_file(&index_path);
+        let mut engine = default_engine();
+        let mut board = Board::startpos();
+        board.play_uci("e2e4").unwrap();
+        set_board(&mut engine, board.clone());
+
+        // The index knows nothing after 1. e4 and the player is not set.
+        let mut index = LocalIndex::new(&index_path, index::DEFAULT_MAX_POSITIONS);
+        index.import("1. d4 d5 1-0\n".as_bytes(), index::DEFAULT_MAX_PLY).unwrap();
+        index.flush().unwrap();
+        polyglot::write_book(&book_path, &mut [book_entry(&board, "e7e5", 1)]).unwrap();
+        {
+            let mut explorer = lock_explorer(&engine);
+            explorer.cache.set_path(None);
+            explorer.sources.local.set_path(Some(index_path.clone()));
+            explorer.sources.polyglot.set_path(Some(book_path.clone()));
+        }
+
+        engine.source = SourceKind::Player;
+        engine.fallback_sources = vec![SourceKind::Local];
+        let position = get_source_position_info(&engine.explorer, &get_lookup(&engine, Duration::from_millis(100))).unwrap();
+        assert!(position.moves.is_empty());
+
+        engine.fallback_sources = vec![SourceKind::Local, SourceKind::Polyglot, SourceKind::Local];
+        let position = get_source_position_info(&engine.explorer, &get_lookup(&engine, Duration::from_millis(100))).unwrap();
+        assert!(position.book_weights);
+        assert_eq!(position.moves[0].uci, "e7e5");
+        let _ = fs::remove_file(&index_path);
+        let _ = fs::remove_file(&book_path);
+    }
+
+    #[test]
+    fn prefetches_do_not_back_off() {
+        let mut engine = default_engine();
+        {
+            let mut explorer = lock_explorer(&engine);
+            explorer.cache.set_path(None);
+            let mut endpoint = explorer.sources.endpoint().clone();
+            endpoint.base_url = "http://127.0.0.1:1".to_string();
+            endpoint.retries = 0;
+            explorer.sources.set_endpoint(endpoint);
+        }
+        engine.opt_network_backoff = 60;
+        let mut lookup = get_lookup(&engine, Duration::from_millis(1000));
+
+        lookup.prefetch = true;
+        lock_explorer(&engine).live_lookups = 1;
+        assert!(matches!(get_position_info_cached(&engine.explorer, &lookup, SourceKind::Lichess), Err(ExplorerError::Unavailable(_))));
+        lock_explorer(&engine).live_lookups = 0;
+        assert!(matches!(get_position_info_cached(&engine.explorer, &lookup, SourceKind::Lichess), Err(ExplorerError::Http(_))));
+        assert!(lock_explorer(&engine).offline_until.is_none());
+
+        lookup.prefetch = false;
+        assert!(get_position_info_cached(&engine.explorer, &lookup, SourceKind::Lichess).is_err());
+        assert!(lock_explorer(&engine).offline_until.is_some());
+    }
+}
@@ -0,0 +1,327 @@
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::{lock_explorer, reset_rng, source, BookExit, Engine, OutOfBook, SortBy, WeightBy};
+use crate::source::{ExplorerApi, SourceKind};
+use crate::uci::{OptionDecl, OptionType, EMPTY_VALUE, FALSE_VALUE, TRUE_VALUE};
+
+const LIST_SEPARATOR: &str = ",";
+
+// What the GUI is told about an option and what setting it does. Values are
+// checked against the declaration before the setter sees them. Defaults are
+// read from the engine only when the GUI is told about them.
+pub enum Kind {
+    Check { default: fn(&Engine) -> bool, set: fn(&mut Engine, bool) },
+    Spin { default: fn(&Engine) -> i64, min: i64, max: i64, set: fn(&mut Engine, i64) },
+    Combo { default: fn(&Engine) -> &'static str, vars: Vec<&'static str>, set: fn(&mut Engine, &str) },
+    // An empty value or <empty> reaches the setter as None.
+    String { default: fn(&Engine) -> Option<String>, set: fn(&mut Engine, Option<&str>) },
+    // A comma separated list of vars, shown to the GUI as a string.
+    List { default: fn(&Engine) -> Vec<&'static str>, vars: Vec<&'static str>, set: fn(&mut Engine, Vec<&'static str>) },
+    Button { press: fn(&mut Engine) },
+}
+
+// The mode of the wrapper an option applies to; in the other mode it is
+// neither shown nor set.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Any,
+    Engine,
+    BookOnly,
+}
+
+pub struct EngineOption {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub mode: Mode,
+}
+
+fn check(name: &'static str, default: fn(&Engine) -> bool, set: fn(&mut Engine, bool)) -> EngineOption {
+    EngineOption { name, kind: Kind::Check { default, set }, mode: Mode::Any }
+}
+
+fn spin(name: &'static str, default: fn(&Engine) -> i64, min: i64, max: i64, set: fn(&mut Engine, i64)) -> EngineOption {
+    EngineOption { name, kind: Kind::Spin { default, min, max, set }, mode: Mode::Any }
+}
+
+fn combo(name: &'static str, default: fn(&Engine) -> &'static str, vars: &[&'static str], set: fn(&mut Engine, &str)) -> EngineOption {
+    EngineOption { name, kind: Kind::Combo { default, vars: vars.to_vec(), set }, mode: Mode::Any }
+}
+
+fn string(name: &'static str, default: fn(&Engine) -> Option<String>, set: fn(&mut Engine, Option<&str>)) -> EngineOption {
+    EngineOption { name, kind: Kind::String { default, set }, mode: Mode::Any }
+}
+
+fn list(name: &'static str, default: fn(&Engine) -> Vec<&'static str>, vars: &[&'static str], set: fn(&mut Engine, Vec<&'static str>)) -> EngineOption {
+    EngineOption { name, kind: Kind::List { default, vars: vars.to_vec(), set }, mode: Mode::Any }
+}
+
+fn button(name: &'static str, press: fn(&mut Engine)) -> EngineOption {
+    EngineOption { name, kind: Kind::Button { press }, mode: Mode::Any }
+}
+
+// Every option the wrapper handles itself, in the order the GUI sees them.
+// Defaults come from the engine and the explorer, so a config file shows up
+// as the default in the GUI.
+pub fn registry() -> &'static [EngineOption] {
+    static REGISTRY: OnceLock<Vec<EngineOption>> = OnceLock::new();
+    REGISTRY.get_or_init(declare)
+}
+
+fn declare() -> Vec<EngineOption> {
+    let source_vars: Vec<&str> = source::SOURCE_KINDS.iter().map(|x| x.name()).collect();
+    vec![
+        check("LichessDB_Masters", |engine| engine.master_games, |engine, x| engine.master_games = x),
+        check("LichessDB_Bullet", |engine| engine.tc.bullet, |engine, x| engine.tc.bullet = x),
+        check("LichessDB_Blitz", |engine| engine.tc.blitz, |engine, x| engine.tc.blitz = x),
+        check("LichessDB_Rapid", |engine| engine.tc.rapid, |engine, x| engine.tc.rapid = x),
+        check("LichessDB_Classical", |engine| engine.tc.classical, |engine, x| engine.tc.classical = x),
+        check("LichessDB_Rating_1600_1800", |engine| engine.ratings.rating_1600, |engine, x| engine.ratings.rating_1600 = x),
+        check("LichessDB_Rating_1800_2000", |engine| engine.ratings.rating_1800, |engine, x| engine.ratings.rating_1800 = x),
+        check("LichessDB_Rating_2000_2200", |engine| engine.ratings.rating_2000, |engine, x| engine.ratings.rating_2000 = x),
+        check("LichessDB_Rating_2200_2500", |engine| engine.ratings.rating_2200, |engine, x| engine.ratings.rating_2200 = x),
+        check("LichessDB_Rating_Above_2500", |engine| engine.ratings.rating_2500, |engine, x| engine.ratings.rating_2500 = x),
+        spin("LichessDB_Games_GT", |engine| engine.opt_games_min as i64, 1, 1000000000, |engine, x| engine.opt_games_min = x as u64),
+        spin("LichessDB_Games_Percent_GT", |engine| engine.opt_games_pct_min as i64, 0, 100, |engine, x| engine.opt_games_pct_min = x as u64),
+        spin("LichessDB_Score_GT", |engine| engine.opt_score_pct_min as i64, 0, 100, |engine, x| engine.opt_score_pct_min = x as u64),
+        combo("LichessDB_Sort_By", |engine| match engine.opt_sortby {
+            SortBy::Games => "Games",
+            SortBy::Score => "Score"
+        }, &["Games", "Score"], |engine, x| engine.opt_sortby = match x {
+            "Score" => SortBy::Score,
+            _ => SortBy::Games
+        }),
+        spin("LichessDB_Variants", |engine| engine.opt_variants as i64, 0, 50, |engine, x| engine.opt_variants = x as usize),
+        combo("LichessDB_Variant_Weight", |engine| match engine.opt_weightby {
+            WeightBy::Games => "Games",
+            WeightBy::Score => "Score",
+            WeightBy::Random => "Random"
+        }, &["Games", "Score", "Random"], |engine, x| engine.opt_weightby = match x {
+            "Games" => WeightBy::Games,
+            "Score" => WeightBy::Score,
+            _ => WeightBy::Random
+        }),
+        combo("LichessDB_Source", |engine| engine.source.name(), &source_vars, |engine, x| {
+            if let Some(kind) = SourceKind::from_name(x) {
+                engine.source = kind;
+            }
+        }),
+        list("LichessDB_Source_Fallback", |engine| engine.fallback_sources.iter().map(|x| x.name()).collect(), &source_vars, |engine, x| {
+            engine.fallback_sources = x.into_iter().filter_map(SourceKind::from_name).collect();
+        }),
+        string("LichessDB_Explorer_URL", |engine| Some(lock_explorer(engine).sources.endpoint().base_url.clone()), |engine, x| {
+            let mut explorer = lock_explorer(engine);
+            let mut endpoint = explorer.sources.endpoint().clone();
+            endpoint.base_url = x.unwrap_or(source::DEFAULT_EXPLORER_URL).to_string();
+            explorer.sources.set_endpoint(endpoint);
+            explorer.offline_until = None;
+        }),
+        combo("LichessDB_Explorer_API", |engine| lock_explorer(engine).sources.endpoint().api.name(), &[ExplorerApi::Current.name(), ExplorerApi::Legacy.name()], |engine, x| {
+            if let Some(api) = ExplorerApi::from_name(x) {
+                let mut explorer = lock_explorer(engine);
+                let mut endpoint = explorer.sources.endpoint().clone();
+                endpoint.api = api;
+                explorer.sources.set_endpoint(endpoint);
+            }
+        }),
+        spin("LichessDB_Request_Timeout", |engine| engine.opt_request_timeout as i64, 100, 60000, |engine, x| {
+            engine.opt_request_timeout = x as u64;
+            lock_explorer(engine).sources.set_request_timeout(Duration::from_millis(x as u64));
+        }),
+        spin("LichessDB_Request_Retries", |engine| i64::from(lock_explorer(engine).sources.endpoint().retries), 0, 10, |engine, x| {
+            let mut explorer = lock_explorer(engine);
+            let mut endpoint = explorer.sources.endpoint().clone();
+            endpoint.retries = x as u32;
+            explorer.sources.set_endpoint(endpoint);
+        }),
+        string("LichessDB_Player", |engine| lock_explorer(engine).sources.player.player.clone(), |engine, x| lock_explorer(engine).sources.player.player = x.map(|x| x.to_string())),
+        string("LichessDB_Local_Index", |engine| lock_explorer(engine).sources.local.path().map(|x| x.display().to_string()), |engine, x| lock_explorer(engine).sources.local.set_path(x.map(PathBuf::from))),
+        string("LichessDB_Polyglot_Book", |engine| lock_explorer(engine).sources.polyglot.path().map(|x| x.display().to_string()), |engine, x| lock_explorer(engine).sources.polyglot.set_path(x.map(PathBuf::from))),
+        string("LichessDB_Cache_File", |engine| lock_explorer(engine).cache.path().map(|x| x.display().to_string()), |engine, x| lock_explorer(engine).cache.set_path(x.map(PathBuf::from))),
+        // For both, 0 means no limit.
+        spin("LichessDB_Cache_Size", |engine| lock_explorer(engine).cache.max_entries() as i64, 0, 100000000, |engine, x| lock_explorer(engine).cache.set_max_entries(x as usize)),
+        spin("LichessDB_Cache_TTL_Hours", |engine| lock_explorer(engine).cache.ttl_hours() as i64, 0, 100000, |engine, x| lock_explorer(engine).cache.set_ttl_hours(x as u64)),
+        button("LichessDB_Clear_Cache", |engine| lock_explorer(engine).cache.clear()),
+        spin("LichessDB_Network_Backoff", |engine| engine.opt_network_backoff as i64, 0, 86400, |engine, x| {
+            engine.opt_network_backoff = x as u64;
+            lock_explorer(engine).offline_until = None;
+        }),
+        spin("LichessDB_Prefetch", |engine| engine.opt_prefetch as i64, 0, 20, |engine, x| engine.opt_prefetch = x as usize),
+        check("LichessDB_Eval_Guard", |engine| engine.opt_eval_guard, |engine, x| engine.opt_eval_guard = x).only(Mode::Engine),
+        spin("LichessDB_Max_Eval_Drop", |engine| engine.opt_max_eval_drop, 0, 10000, |engine, x| engine.opt_max_eval_drop = x).only(Mode::Engine),
+        spin("LichessDB_Eval_Depth", |engine| i64::from(engine.opt_eval_depth), 1, 40, |engine, x| engine.opt_eval_depth = x as u32).only(Mode::Engine),
+        combo("LichessDB_Book_Exit", |engine| match engine.opt_book_exit {
+            BookExit::Off => "Off",
+            BookExit::Latch => "Latch",
+            BookExit::Transpositions => "Transpositions"
+        }, &["Off", "Latch", "Transpositions"], |engine, x| engine.opt_book_exit = match x {
+            "Latch" => BookExit::Latch,
+            "Transpositions" => BookExit::Transpositions,
+            _ => BookExit::Off
+        }),
+        spin("LichessDB_Max_Ply", |engine| i64::from(engine.opt_max_ply), 0, 1000, |engine, x| engine.opt_max_ply = x as u32),
+        spin("LichessDB_Min_Time", |engine| engine.opt_min_time as i64, 0, 3600000, |engine, x| engine.opt_min_time = x as u64),
+        spin("LichessDB_Seed", |engine| engine.opt_seed as i64, 0, 2147483647, |engine, x| {
+            engine.opt_seed = x as u64;
+            reset_rng(engine);
+        }),
+        spin("LichessDB_Engine_Timeout", |engine| engine.opt_engine_timeout as i64, 0, 600000, |engine, x| engine.opt_engine_timeout = x as u64).only(Mode::Engine),
+        // Without an engine to fall back on, running out of book needs its own answer.
+        combo("LichessDB_Out_Of_Book", |engine| match engine.opt_out_of_book {
+            OutOfBook::Resign => "Resign",
+            OutOfBook::Random => "Random",
+            OutOfBook::Null => "Null"
+        }, &["Resign", "Random", "Null"], |engine, x| engine.opt_out_of_book = match x {
+            "Random" => OutOfBook::Random,
+            "Null" => OutOfBook::Null,
+            _ => OutOfBook::Resign
+        }).only(Mode::BookOnly),
+    ]
+}
+
+// Option names are not case sensitive.
+pub fn find(name: &str) -> Option<&'static EngineOption> {
+    registry().iter().find(|x| x.name.eq_ignore_ascii_case(name))
+}
+
+impl EngineOption {
+    fn only(self, mode: Mode) -> EngineOption {
+        EngineOption { mode, ..self }
+    }
+
+    pub fn available(&self, engine: &Engine) -> bool {
+        match self.mode {
+            Mode::Any => true,
+            Mode::Engine => !engine.book_only,
+            Mode::BookOnly => engine.book_only
+        }
+    }
+
+    pub fn decl(&self, engine: &Engine) -> OptionDecl {
+        let kind = match &self.kind {
+            Kind::Check { default, .. } => OptionType::Check(default(engine)),
+            Kind::Spin { default, min, max, .. } => OptionType::Spin { default: default(engine), min: *min, max: *max },
+            Kind::Combo { default, vars, .. } => OptionType::Combo { default: default(engine).to_string(), vars: vars.iter().map(|x| x.to_string()).collect() },
+            Kind::String { default, .. } => OptionType::String(default(engine).unwrap_or_else(|| EMPTY_VALUE.to_string())),
+            Kind::List { default, .. } => match default(engine) {
+                list if list.is_empty() => OptionType::String(EMPTY_VALUE.to_string()),
+                list => OptionType::String(list.join(LIST_SEPARATOR)),
+            },
+            Kind::Button { .. } => OptionType::Button,
+        };
+        OptionDecl { name: self.name.to_string(), kind }
+    }
+
+    pub fn set(&self, engine: &mut Engine, value: Option<&str>) -> Result<(), String> {
+        match &self.kind {
+            Kind::Check { set, .. } => match value {
+                Some(TRUE_VALUE) => set(engine, true),
+                Some(FALSE_VALUE) => set(engine, false),
+                _ => return Err(self.invalid(value, "true or false".to_string()))
+            },
+            Kind::Spin { min, max, set, .. } => match value.and_then(|x| x.parse::<i64>().ok()) {
+                Some(x) if x >= *min && x <= *max => set(engine, x),
+                _ => return Err(self.invalid(value, format!("a number from {} to {}", min, max)))
+            },
+            Kind::Combo { vars, set, .. } => match value.and_then(|x| vars.iter().find(|var| var.eq_ignore_ascii_case(x))) {
+                Some(var) => set(engine, var),
+                None => return Err(self.invalid(value, format!("one of {}", vars.join(", "))))
+            },
+            Kind::String { set, .. } => match value {
+                None | Some("") | Some(EMPTY_VALUE) => set(engine, None),
+                Some(x) => set(engine, Some(x))
+            },
+            Kind::List { vars, set, .. } => match value {
+                None | Some("") | Some(EMPTY_VALUE) => set(engine, vec![]),
+                Some(x) => {
+                    let mut list = vec![];
+                    for item in x.split(LIST_SEPARATOR).map(|x| x.trim()) {
+                        match vars.iter().find(|var| var.eq_ignore_ascii_case(item)) {
+                            Some(var) => list.push(*var),
+                            None => return Err(self.invalid(value, format!("a list of {} separated by {}", vars.join(", "), LIST_SEPARATOR)))
+                        }
+                    }
+                    set(engine, list)
+                }
+            },
+            Kind::Button { press } => press(engine),
+        }
+        Ok(())
+    }
+
+    fn invalid(&self, value: Option<&str>, expected: String) -> String {
+        match value {
+            Some(x) => format!("Invalid value {} for {}, expected {}", x, self.name, expected),
+            None => format!("Missing value for {}, expected {}", self.name, expected)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default_engine;
+
+    #[test]
+    fn lists_keep_their_order() {
+        let mut engine = default_engine();
+        let option = find("LichessDB_Source_Fallback").unwrap();
+        option.set(&mut engine, Some("polyglot, Local,Lichess")).unwrap();
+        assert!(engine.fallback_sources == vec![SourceKind::Polyglot, SourceKind::Local, SourceKind::Lichess]);
+        assert_eq!(option.decl(&engine).kind, OptionType::String("Polyglot,Local,Lichess".to_string()));
+
+        assert!(option.set(&mut engine, Some("Local,Nowhere")).is_err());
+        assert_eq!(engine.fallback_sources.len(), 3);
+        option.set(&mut engine, Some(EMPTY_VALUE)).unwrap();
+        assert!(engine.fallback_sources.is_empty());
+    }
+
+    #[test]
+    fn spins_are_range_checked() {
+        let mut engine = default_engine();
+        let option = find("lichessdb_games_percent_gt").unwrap();
+        option.set(&mut engine, Some("0")).unwrap();
+        option.set(&mut engine, Some("100")).unwrap();
+        assert_eq!(engine.opt_games_pct_min, 100);
+        for value in [Some("-1"), Some("101"), Some("ten"), Some(""), None].iter() {
+            assert!(option.set(&mut engine, *value).is_err());
+        }
+        assert_eq!(engine.opt_games_pct_min, 100);
+    }
+
+    #[test]
+    fn combos_take_only_their_vars() {
+        let mut engine = default_engine();
+        let option = find("LichessDB_Variant_Weight").unwrap();
+        option.set(&mut engine, Some("score")).unwrap();
+        assert!(matches!(engine.opt_weightby, WeightBy::Score));
+        assert_eq!(option.decl(&engine).kind, OptionType::Combo { default: "Score".to_string(), vars: vec!["Games".to_string(), "Score".to_string(), "Random".to_string()] });
+        assert!(option.set(&mut engine, Some("Elo")).is_err());
+        assert!(option.set(&mut engine, None).is_err());
+        assert!(matches!(engine.opt_weightby, WeightBy::Score));
+    }
+
+    #[test]
+    fn checks_take_true_or_false() {
+        let mut engine = default_engine();
+        let option = find("LichessDB_Masters").unwrap();
+        option.set(&mut engine, Some(TRUE_VALUE)).unwrap();
+        assert!(engine.master_games);
+        option.set(&mut engine, Some(FALSE_VALUE)).unwrap();
+        assert!(!engine.master_games);
+        assert!(option.set(&mut engine, Some("yes")).is_err());
+        assert!(option.set(&mut engine, None).is_err());
+    }
+
+    #[test]
+    fn options_follow_the_mode() {
+        let mut engine = default_engine();
+        assert!(find("LichessDB_Eval_Guard").unwrap().available(&engine));
+        assert!(!find("LichessDB_Out_Of_Book").unwrap().available(&engine));
+        engine.book_only = true;
+        assert!(!find("LichessDB_Eval_Guard").unwrap().available(&engine));
+        assert!(find("LichessDB_Out_Of_Book").unwrap().available(&engine));
+        assert!(find("LichessDB_Source").unwrap().available(&engine));
+    }
+}
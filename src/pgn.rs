@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum GameResult {
+    White,
+    Draw,
+    Black,
+}
+
+pub struct PgnGame {
+    pub tags: HashMap<String, String>,
+    pub moves: Vec<String>,
+    pub result: Option<GameResult>,
+}
+
+pub struct PgnReader<R> {
+    lines: io::Lines<R>,
+    pending_tag: Option<String>,
+}
+
+impl PgnGame {
+    fn new() -> PgnGame {
+        PgnGame {
+            tags: HashMap::new(),
+            moves: vec![],
+            result: None,
+        }
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.get(name).map(|x| x.as_str())
+    }
+}
+
+fn parse_tag(line: &str) -> Option<(String, String)> {
+    let inner = line.trim().trim_start_matches('[').trim_end_matches(']');
+    let mut parts = inner.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(name), Some(value)) => Some((name.to_string(), value.trim().trim_matches('"').replace("\\\"", "\""))),
+        _ => None
+    }
+}
+
+fn parse_result(token: &str) -> Option<Option<GameResult>> {
+    match token {
+        "1-0" => Some(Some(GameResult::White)),
+        "0-1" => Some(Some(GameResult::Black)),
+        "1/2-1/2" => Some(Some(GameResult::Draw)),
+        "*" => Some(None),
+        _ => None
+    }
+}
+
+impl<R: BufRead> PgnReader<R> {
+    pub fn new(reader: R) -> PgnReader<R> {
+        PgnReader {
+            lines: reader.lines(),
+            pending_tag: None,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for PgnReader<R> {
+    type Item = io::Result<PgnGame>;
+
+    fn next(&mut self) -> Option<io::Result<PgnGame>> {
+        let mut game = PgnGame::new();
+        let mut in_movetext = false;
+        let mut comment = false;
+        let mut variation_depth = 0;
+
+        if let Some(line) = self.pending_tag.take() {
+            if let Some((name, value)) = parse_tag(line.as_str()) {
+                game.tags.insert(name, value);
+            }
+        }
+
+        loop {
+            let line = match self.lines.next() {
+                Some(Ok(line)) => line,
+                Some(Err(err)) => return Some(Err(err)),
+                None => {
+                    if game.tags.is_empty() && game.moves.is_empty() {
+                        return None
+                    }
+                    return Some(Ok(game))
+                }
+            };
+
+            if !comment && line.trim_start().starts_with('[') {
+                if in_movetext {
+                    // A new tag section without a result terminator starts the next game.
+                    self.pending_tag = Some(line);
+                    return Some(Ok(game))
+                }
+                if let Some((name, value)) = parse_tag(line.as_str()) {
+                    game.tags.insert(name, value);
+                }
+                continue
+            }
+            if !comment && line.starts_with('%') {
+                continue
+            }
+
+            let mut token = String::new();
+            let mut tokens = vec![];
+            for c in line.chars() {
+                if comment {
+                    if c == '}' {
+                        comment = false;
+                    }
+                    continue
+                }
+                match c {
+                    '{' | '(' | ')' | ';' => {
+                        if !token.is_empty() {
+                            tokens.push((variation_depth, token.clone()));
+                            token.clear();
+                        }
+                        match c {
+                            '{' => comment = true,
+                            '(' => variation_depth += 1,
+                            ')' => if variation_depth > 0 { variation_depth -= 1 },
+                            _ => break
+                        }
+                    }
+                    c if c.is_whitespace() => {
+                        if !token.is_empty() {
+                            tokens.push((variation_depth, token.clone()));
+                            token.clear();
+                        }
+                    }
+                    c => token.push(c)
+                }
+            }
+            if !token.is_empty() {
+                tokens.push((variation_depth, token));
+            }
+
+            for (depth, token) in tokens {
+                in_movetext = true;
+                if depth > 0 || token.starts_with('$') {
+                    continue
+                }
+                if let Some(result) = parse_result(token.as_str()) {
+                    game.result = result;
+                    return Some(Ok(game))
+                }
+                let san = match token.rfind('.') {
+                    Some(i) => &token[i + 1..],
+                    None => token.as_str()
+                };
+                if !san.is_empty() {
+                    game.moves.push(san.to_string());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(pgn: &str) -> Vec<PgnGame> {
+        PgnReader::new(pgn.as_bytes()).map(|x| x.unwrap()).collect()
+    }
+
+    #[test]
+    fn reads_tags_moves_and_result() {
+        let games = read("[Event \"Test\"]\n[White \"A \\\"B\\\" C\"]\n\n1. e4 e5 2. Nf3 Nc6 1-0\n");
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].tag("Event"), Some("Test"));
+        assert_eq!(games[0].tag("White"), Some("A \"B\" C"));
+        assert_eq!(games[0].moves, vec!["e4", "e5", "Nf3", "Nc6"]);
+        assert!(games[0].result == Some(GameResult::White));
+    }
+
+    #[test]
+    fn skips_comments_variations_and_nags() {
+        let games = read("1. e4 {best by test} e5 (1... c5 2. Nf3 (2. c3)) 2. Nf3 $1 ; rest of line\n2... Nc6 {multi\nline} 3. Bb5 1/2-1/2\n");
+        assert_eq!(games[0].moves, vec!["e4", "e5", "Nf3", "Nc6", "Bb5"]);
+        assert!(games[0].result == Some(GameResult::Draw));
+    }
+
+    #[test]
+    fn splits_games() {
+        let games = read("[Event \"1\"]\n\n1. d4 0-1\n\n[Event \"2\"]\n\n1. c4 *\n[Event \"3\"]\n1. Nf3\n[Event \"4\"]\n1. g3\n");
+        assert_eq!(games.len(), 4);
+        assert!(games[0].result == Some(GameResult::Black));
+        assert!(games[1].result.is_none());
+        assert_eq!(games[2].tag("Event"), Some("3"));
+        assert_eq!(games[2].moves, vec!["Nf3"]);
+        assert_eq!(games[3].tag("Event"), Some("4"));
+        assert_eq!(games[3].moves, vec!["g3"]);
+    }
+}
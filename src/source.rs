@@ -0,0 +1,454 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::board::{Board, Color};
+use crate::index::{self, IndexFile};
+use crate::polyglot::PolyglotBook;
+use crate::{PositionInfo, RatingFilter, TimeControlFilter};
+
+pub struct Filters<'a> {
+    pub ratings: &'a RatingFilter,
+    pub tc: &'a TimeControlFilter,
+}
+
+pub trait ExplorerSource {
+    // Sources backed by the network return a key so their answers are kept in
+    // the explorer cache; local sources return None.
+    fn cache_key(&self, board: &Board, filters: &Filters) -> Option<String>;
+
+    fn position_info(&mut self, board: &Board, filters: &Filters) -> Result<PositionInfo, ExplorerError>;
+}
+
+pub enum ExplorerError {
+    Unavailable(String),
+    Http(String),
+    Timeout,
+    Status(u16),
+    RateLimited(Option<Duration>),
+    Parse(String),
+}
+
+impl fmt::Display for ExplorerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExplorerError::Unavailable(err) => write!(f, "{}", err),
+            ExplorerError::Http(err) => write!(f, "HTTP request error: {}", err),
+            ExplorerError::Timeout => write!(f, "HTTP request timed out"),
+            ExplorerError::Status(status) => write!(f, "HTTP status {}", status),
+            ExplorerError::RateLimited(Some(retry_after)) => write!(f, "Rate limited, retry after {}s", retry_after.as_secs()),
+            ExplorerError::RateLimited(None) => write!(f, "Rate limited"),
+            ExplorerError::Parse(err) => write!(f, "JSON parse error: {}", err),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    Masters,
+    Lichess,
+    Player,
+    Local,
+    Polyglot,
+}
+
+pub const SOURCE_KINDS: [SourceKind; 5] = [SourceKind::Masters, SourceKind::Lichess, SourceKind::Player, SourceKind::Local, SourceKind::Polyglot];
+
+impl SourceKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            SourceKind::Masters => "Masters",
+            SourceKind::Lichess => "Lichess",
+            SourceKind::Player => "Player",
+            SourceKind::Local => "Local",
+            SourceKind::Polyglot => "Polyglot",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SourceKind> {
+        SOURCE_KINDS.iter().find(|x| x.name() == name).cloned()
+    }
+}
+
+pub const DEFAULT_EXPLORER_URL: &str = "https://explorer.lichess.ovh";
+pub const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 5000;
+pub const DEFAULT_REQUEST_RETRIES: u32 = 2;
+
+const RETRY_DELAY_MS: u64 = 250;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ExplorerApi {
+    Current,
+    Legacy,
+}
+
+#[derive(Clone)]
+pub struct Endpoint {
+    pub base_url: String,
+    pub api: ExplorerApi,
+    // Total time allowed for a lookup, retries included.
+    pub timeout: Duration,
+    pub retries: u32,
+    // Shared by every copy of the endpoint, so connections are reused.
+    client: Result<reqwest::Client, String>,
+}
+
+#[derive(Clone)]
+pub struct LichessMasters {
+    endpoint: Endpoint,
+}
+
+#[derive(Clone)]
+pub struct LichessGames {
+    endpoint: Endpoint,
+}
+
+#[derive(Clone)]
+pub struct LichessPlayer {
+    endpoint: Endpoint,
+    pub player: Option<String>,
+}
+
+pub struct LocalIndexSource {
+    path: Option<PathBuf>,
+    index: Option<IndexFile>,
+}
+
+pub struct PolyglotSource {
+    path: Option<PathBuf>,
+    book: Option<PolyglotBook>,
+}
+
+pub struct Sources {
+    pub masters: LichessMasters,
+    pub lichess: LichessGames,
+    pub player: LichessPlayer,
+    pub local: LocalIndexSource,
+    pub polyglot: PolyglotSource,
+}
+
+impl Sources {
+    pub fn new() -> Sources {
+        let timeout = Duration::from_millis(DEFAULT_REQUEST_TIMEOUT_MS);
+        let endpoint = Endpoint {
+            base_url: DEFAULT_EXPLORER_URL.to_string(),
+            api: ExplorerApi::Current,
+            timeout,
+            retries: DEFAULT_REQUEST_RETRIES,
+            client: build_client(timeout),
+        };
+        Sources {
+            masters: LichessMasters { endpoint: endpoint.clone() },
+            lichess: LichessGames { endpoint: endpoint.clone() },
+            player: LichessPlayer { endpoint, player: None },
+            local: LocalIndexSource { path: None, index: None },
+            polyglot: PolyglotSource { path: None, book: None },
+        }
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.lichess.endpoint
+    }
+
+    pub fn set_endpoint(&mut self, endpoint: Endpoint) {
+        self.masters.endpoint = endpoint.clone();
+        self.lichess.endpoint = endpoint.clone();
+        self.player.endpoint = endpoint;
+    }
+
+    // A single request is cut off at the request timeout, whatever is left of
+    // a lookup's budget.
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        let mut endpoint = self.endpoint().clone();
+        endpoint.timeout = timeout;
+        endpoint.client = build_client(timeout);
+        self.set_endpoint(endpoint);
+    }
+
+    // A copy of a network source with its own time budget, so a request can
+    // run without holding the explorer. Local sources return None.
+    pub fn remote(&self, kind: SourceKind, timeout: Duration) -> Option<Box<dyn ExplorerSource>> {
+        let mut endpoint = self.endpoint().clone();
+        endpoint.timeout = timeout;
+        match kind {
+            SourceKind::Masters => Some(Box::new(LichessMasters { endpoint })),
+            SourceKind::Lichess => Some(Box::new(LichessGames { endpoint })),
+            SourceKind::Player => Some(Box::new(LichessPlayer { endpoint, player: self.player.player.clone() })),
+            SourceKind::Local | SourceKind::Polyglot => None
+        }
+    }
+
+    pub fn get(&mut self, kind: SourceKind) -> &mut dyn ExplorerSource {
+        match kind {
+            SourceKind::Masters => &mut self.masters,
+            SourceKind::Lichess => &mut self.lichess,
+            SourceKind::Player => &mut self.player,
+            SourceKind::Local => &mut self.local,
+            SourceKind::Polyglot => &mut self.polyglot,
+        }
+    }
+}
+
+impl ExplorerApi {
+    pub fn name(self) -> &'static str {
+        match self {
+            ExplorerApi::Current => "Current",
+            ExplorerApi::Legacy => "Legacy",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ExplorerApi> {
+        match name {
+            "Current" => Some(ExplorerApi::Current),
+            "Legacy" => Some(ExplorerApi::Legacy),
+            _ => None
+        }
+    }
+}
+
+impl Endpoint {
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url.trim_end_matches('/'), path)
+    }
+
+    // Answers from other servers are kept apart from the public explorer's.
+    fn cache_key(&self, key: String) -> String {
+        if self.base_url == DEFAULT_EXPLORER_URL {
+            key
+        } else {
+            format!("{} {}", self.base_url, key)
+        }
+    }
+}
+
+fn rating_list(ratings: &RatingFilter) -> Vec<&'static str> {
+    let mut list = vec![];
+    if ratings.rating_1600 { list.push("1600"); }
+    if ratings.rating_1800 { list.push("1800"); }
+    if ratings.rating_2000 { list.push("2000"); }
+    if ratings.rating_2200 { list.push("2200"); }
+    if ratings.rating_2500 { list.push("2500"); }
+    list
+}
+
+fn speed_list(tc: &TimeControlFilter) -> Vec<&'static str> {
+    let mut list = vec![];
+    if tc.bullet { list.push("bullet"); }
+    if tc.blitz { list.push("blitz"); }
+    if tc.rapid { list.push("rapid"); }
+    if tc.classical { list.push("classical"); }
+    list
+}
+
+fn build_client(timeout: Duration) -> Result<reqwest::Client, String> {
+    reqwest::Client::builder().timeout(timeout).build().map_err(|x| x.to_string())
+}
+
+fn fetch_once(client: &reqwest::Client, url: reqwest::Url) -> Result<String, ExplorerError> {
+    let mut resp = match client.get(url).send() {
+        Ok(resp) => resp,
+        Err(ref err) if err.is_timeout() => return Err(ExplorerError::Timeout),
+        Err(err) => return Err(ExplorerError::Http(err.to_string()))
+    };
+    match resp.status() {
+        reqwest::StatusCode::TOO_MANY_REQUESTS => {
+            let retry_after = resp.headers().get(reqwest::header::RETRY_AFTER)
+                .and_then(|x| x.to_str().ok())
+                .and_then(|x| x.trim().parse().ok())
+                .map(Duration::from_secs);
+            return Err(ExplorerError::RateLimited(retry_after))
+        }
+        status if !status.is_success() => return Err(ExplorerError::Status(status.as_u16())),
+        _ => ()
+    }
+    match resp.text() {
+        Ok(text) => Ok(text),
+        Err(ref err) if err.is_timeout() => Err(ExplorerError::Timeout),
+        Err(err) => Err(ExplorerError::Http(err.to_string()))
+    }
+}
+
+// Connection errors, timeouts and server errors are retried with a doubling
+// delay as long as the endpoint's time budget allows; rate limits are not.
+fn fetch_text(endpoint: &Endpoint, path: &str, params: &[(&str, &str)]) -> Result<String, ExplorerError> {
+    let url = match reqwest::Url::parse_with_params(endpoint.url(path).as_str(), params) {
+        Ok(url) => url,
+        Err(err) => return Err(ExplorerError::Unavailable(format!("Url parse error: {}", err)))
+    };
+    let client = match &endpoint.client {
+        Ok(client) => client,
+        Err(err) => return Err(ExplorerError::Http(err.clone()))
+    };
+    let deadline = Instant::now() + endpoint.timeout;
+    let mut delay = Duration::from_millis(RETRY_DELAY_MS);
+    let mut attempt = 0;
+    loop {
+        if Instant::now() >= deadline {
+            return Err(ExplorerError::Timeout)
+        }
+        let err = match fetch_once(client, url.clone()) {
+            Ok(text) => return Ok(text),
+            Err(err @ ExplorerError::Http(_)) | Err(err @ ExplorerError::Timeout) => err,
+            Err(ExplorerError::Status(status)) if status >= 500 => ExplorerError::Status(status),
+            Err(err) => return Err(err)
+        };
+        attempt += 1;
+        if attempt > endpoint.retries || Instant::now() + delay >= deadline {
+            return Err(err)
+        }
+        thread::sleep(delay);
+        delay *= 2;
+    }
+}
+
+fn parse_position(text: &str) -> Result<PositionInfo, ExplorerError> {
+    match serde_json::from_str(text) {
+        Ok(position) => Ok(position),
+        Err(err) => Err(ExplorerError::Parse(err.to_string()))
+    }
+}
+
+impl ExplorerSource for LichessMasters {
+    fn cache_key(&self, board: &Board, _filters: &Filters) -> Option<String> {
+        Some(self.endpoint.cache_key(format!("master {}", index::position_key(board.fen().as_str()))))
+    }
+
+    fn position_info(&mut self, board: &Board, _filters: &Filters) -> Result<PositionInfo, ExplorerError> {
+        let fen = board.fen();
+        let params = vec![("fen", fen.as_str()), ("moves", "50")];
+        let path = match self.endpoint.api {
+            ExplorerApi::Current => "masters",
+            ExplorerApi::Legacy => "master",
+        };
+        parse_position(fetch_text(&self.endpoint, path, &params)?.as_str())
+    }
+}
+
+impl ExplorerSource for LichessGames {
+    fn cache_key(&self, board: &Board, filters: &Filters) -> Option<String> {
+        Some(self.endpoint.cache_key(format!("lichess ratings={} speeds={} {}", rating_list(filters.ratings).join(","), speed_list(filters.tc).join(","), index::position_key(board.fen().as_str()))))
+    }
+
+    fn position_info(&mut self, board: &Board, filters: &Filters) -> Result<PositionInfo, ExplorerError> {
+        let fen = board.fen();
+        let ratings = rating_list(filters.ratings);
+        let speeds = speed_list(filters.tc);
+        let joined_ratings = ratings.join(",");
+        let joined_speeds = speeds.join(",");
+        let mut params = vec![("fen", fen.as_str()), ("moves", "50"), ("variant", "standard")];
+        match self.endpoint.api {
+            ExplorerApi::Current => {
+                params.push(("ratings", joined_ratings.as_str()));
+                params.push(("speeds", joined_speeds.as_str()));
+            }
+            ExplorerApi::Legacy => {
+                for rating in ratings {
+                    params.push(("ratings[]", rating));
+                }
+                for speed in speeds {
+                    params.push(("speeds[]", speed));
+                }
+            }
+        }
+        parse_position(fetch_text(&self.endpoint, "lichess", &params)?.as_str())
+    }
+}
+
+fn color_name(color: Color) -> &'static str {
+    match color {
+        Color::White => "white",
+        Color::Black => "black",
+    }
+}
+
+// The player explorer is queried for the side to move, so the wrapper plays
+// the configured player's repertoire.
+impl ExplorerSource for LichessPlayer {
+    fn cache_key(&self, board: &Board, filters: &Filters) -> Option<String> {
+        let player = self.player.as_ref()?;
+        Some(self.endpoint.cache_key(format!("player {} {} speeds={} {}", player.to_lowercase(), color_name(board.turn()), speed_list(filters.tc).join(","), index::position_key(board.fen().as_str()))))
+    }
+
+    fn position_info(&mut self, board: &Board, filters: &Filters) -> Result<PositionInfo, ExplorerError> {
+        let player = match &self.player {
+            Some(player) => player.clone(),
+            None => return Err(ExplorerError::Unavailable("LichessDB_Player is not set".to_string()))
+        };
+        if self.endpoint.api == ExplorerApi::Legacy {
+            return Err(ExplorerError::Unavailable("The legacy explorer API has no player endpoint".to_string()))
+        }
+        let fen = board.fen();
+        let speeds = speed_list(filters.tc).join(",");
+        let params = vec![("player", player.as_str()), ("color", color_name(board.turn())), ("fen", fen.as_str()), ("speeds", speeds.as_str()), ("recentGames", "0")];
+        // The response is streamed as NDJSON; the last line holds the final counts.
+        let text = fetch_text(&self.endpoint, "player", &params)?;
+        match text.lines().rev().find(|x| !x.trim().is_empty()) {
+            Some(line) => parse_position(line),
+            None => Err(ExplorerError::Parse("Empty player explorer response".to_string()))
+        }
+    }
+}
+
+impl LocalIndexSource {
+    pub fn path(&self) -> Option<&PathBuf> {
+        self.path.as_ref()
+    }
+
+    pub fn set_path(&mut self, path: Option<PathBuf>) {
+        self.path = path;
+        self.index = None;
+    }
+}
+
+impl ExplorerSource for LocalIndexSource {
+    fn cache_key(&self, _board: &Board, _filters: &Filters) -> Option<String> {
+        None
+    }
+
+    fn position_info(&mut self, board: &Board, filters: &Filters) -> Result<PositionInfo, ExplorerError> {
+        if self.index.is_none() {
+            match &self.path {
+                Some(path) => match IndexFile::open(path) {
+                    Ok(index) => self.index = Some(index),
+                    Err(err) => return Err(ExplorerError::Unavailable(format!("Cannot open local index {}: {}", path.display(), err)))
+                },
+                None => return Err(ExplorerError::Unavailable("LichessDB_Local_Index is not set".to_string()))
+            }
+        }
+        match self.index.as_mut().unwrap().position_info(board.fen().as_str(), filters.ratings, filters.tc) {
+            Ok(position) => Ok(position),
+            Err(err) => Err(ExplorerError::Unavailable(format!("Cannot read local index: {}", err)))
+        }
+    }
+}
+
+impl PolyglotSource {
+    pub fn path(&self) -> Option<&PathBuf> {
+        self.path.as_ref()
+    }
+
+    pub fn set_path(&mut self, path: Option<PathBuf>) {
+        self.path = path;
+        self.book = None;
+    }
+}
+
+impl ExplorerSource for PolyglotSource {
+    fn cache_key(&self, _board: &Board, _filters: &Filters) -> Option<String> {
+        None
+    }
+
+    fn position_info(&mut self, board: &Board, _filters: &Filters) -> Result<PositionInfo, ExplorerError> {
+        if self.book.is_none() {
+            match &self.path {
+                Some(path) => match PolyglotBook::load(path) {
+                    Ok(book) => self.book = Some(book),
+                    Err(err) => return Err(ExplorerError::Unavailable(format!("Cannot load Polyglot book {}: {}", path.display(), err)))
+                },
+                None => return Err(ExplorerError::Unavailable("LichessDB_Polyglot_Book is not set".to_string()))
+            }
+        }
+        Ok(self.book.as_ref().unwrap().position_info(board))
+    }
+}
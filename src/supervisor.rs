@@ -0,0 +1,149 @@
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::Event;
+use crate::uci::{self, Command};
+
+// How to start the wrapped engine. Command-line flags win over environment
+// variables, which win over the config file.
+pub struct EngineCommand {
+    pub path: String,
+    pub args: Vec<String>,
+    pub dir: Option<PathBuf>,
+    pub env: Vec<(String, String)>,
+}
+
+// Owns the engine process. Everything written to it is forwarded line by
+// line, and the lines that shape the engine's state are remembered so a
+// restarted engine can be brought back to the same state.
+pub struct Supervisor {
+    command: EngineCommand,
+    events: mpsc::Sender<Event>,
+    child: Option<process::Child>,
+    stdin: Option<process::ChildStdin>,
+    reader: Option<thread::JoinHandle<()>>,
+    partial: Vec<u8>,
+
+    debug: Option<String>,
+    register: Option<String>,
+    options: Vec<(String, String)>,
+    position: Option<String>,
+}
+
+impl Supervisor {
+    pub fn new(command: EngineCommand, events: mpsc::Sender<Event>) -> Supervisor {
+        Supervisor {
+            command,
+            events,
+            child: None,
+            stdin: None,
+            reader: None,
+            partial: vec![],
+            debug: None,
+            register: None,
+            options: vec![],
+            position: None,
+        }
+    }
+
+    pub fn start(&mut self) -> io::Result<()> {
+        let mut process = process::Command::new(&self.command.path);
+        process.args(&self.command.args)
+            .envs(self.command.env.iter().map(|(name, value)| (name, value)))
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::inherit());
+        if let Some(dir) = &self.command.dir {
+            process.current_dir(dir);
+        }
+        let mut child = match process.spawn() {
+            Ok(child) => child,
+            Err(err) => return Err(io::Error::new(err.kind(), format!("Cannot start engine {}: {}", self.command.path, err)))
+        };
+
+        let stdout = child.stdout.take().unwrap();
+        let events = self.events.clone();
+        self.reader = Some(thread::spawn(move || {
+            for line in io::BufReader::new(stdout).lines() {
+                match line {
+                    Ok(line) => if events.send(Event::EngineLine(line)).is_err() { return },
+                    Err(_) => break
+                }
+            }
+            let _ = events.send(Event::EngineExit);
+        }));
+        self.stdin = child.stdin.take();
+        self.child = Some(child);
+        Ok(())
+    }
+
+    // Kills the engine and waits until everything it printed has been queued,
+    // so the caller can throw its output away before starting a new one.
+    pub fn stop(&mut self) {
+        self.stdin = None;
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+
+    // Closes the engine's input and waits for it to exit on its own.
+    pub fn wait(&mut self) {
+        self.stdin = None;
+        if let Some(mut child) = self.child.take() {
+            let _ = child.wait();
+        }
+    }
+
+    // The lines a new engine needs after the uci handshake.
+    pub fn replay(&self) -> Vec<String> {
+        self.debug.iter()
+            .chain(self.register.iter())
+            .chain(self.options.iter().map(|(_, line)| line))
+            .chain(self.position.iter())
+            .cloned()
+            .collect()
+    }
+
+    fn record(&mut self, line: &str) {
+        match uci::parse_command(line) {
+            Command::Debug(_) => self.debug = Some(line.to_string()),
+            Command::Register(_) => self.register = Some(line.to_string()),
+            Command::SetOption(name, _) => {
+                let name = name.to_lowercase();
+                match self.options.iter().position(|(x, _)| *x == name) {
+                    Some(i) => self.options[i].1 = line.to_string(),
+                    None => self.options.push((name, line.to_string()))
+                }
+            }
+            Command::Position(_) => self.position = Some(line.to_string()),
+            Command::UciNewGame => self.position = None,
+            _ => ()
+        }
+    }
+}
+
+impl Write for Supervisor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        while let Some(i) = self.partial.iter().position(|x| *x == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=i).collect();
+            self.record(String::from_utf8_lossy(&line).trim_end());
+            // A dead engine is noticed through its output, so failed writes are ignored.
+            if let Some(stdin) = &mut self.stdin {
+                let _ = stdin.write_all(&line);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
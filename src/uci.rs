@@ -0,0 +1,722 @@
+use std::fmt;
+
+pub const UCI_CMD: &str        = "uci";
+pub const SETOPTION_CMD: &str  = "setoption";
+pub const ISREADY_CMD: &str    = "isready";
+pub const POSITION_CMD: &str   = "position";
+pub const GO_CMD: &str         = "go";
+pub const STOP_CMD: &str       = "stop";
+pub const PONDERHIT_CMD: &str  = "ponderhit";
+pub const UCINEWGAME_CMD: &str = "ucinewgame";
+pub const DEBUG_CMD: &str      = "debug";
+pub const REGISTER_CMD: &str   = "register";
+pub const QUIT_CMD: &str       = "quit";
+pub const EXIT_CMD: &str       = "exit";
+
+pub const ID_RESP: &str             = "id";
+pub const UCIOK_RESP: &str          = "uciok";
+pub const OPTION_RESP: &str         = "option";
+pub const READYOK_RESP: &str        = "readyok";
+pub const INFO_RESP: &str           = "info";
+pub const BESTMOVE_RESP: &str       = "bestmove";
+pub const COPYPROTECTION_RESP: &str = "copyprotection";
+pub const REGISTRATION_RESP: &str   = "registration";
+
+pub const NAME_PARAM: &str    = "name";
+pub const AUTHOR_PARAM: &str  = "author";
+pub const VALUE_PARAM: &str   = "value";
+pub const MOVES_PARAM: &str   = "moves";
+pub const PONDER_PARAM: &str  = "ponder";
+pub const STRING_PARAM: &str  = "string";
+
+pub const NULL_MOVE: &str     = "0000";
+pub const NO_MOVE: &str       = "(none)";
+
+pub const ON_VALUE: &str    = "on";
+pub const OFF_VALUE: &str   = "off";
+pub const TRUE_VALUE: &str  = "true";
+pub const FALSE_VALUE: &str = "false";
+// A string option set to nothing.
+pub const EMPTY_VALUE: &str = "<empty>";
+
+pub const MULTIPV_OPTION: &str = "MultiPV";
+
+const COMMANDS: [&str; 11] = [UCI_CMD, DEBUG_CMD, ISREADY_CMD, SETOPTION_CMD, REGISTER_CMD, UCINEWGAME_CMD, POSITION_CMD, GO_CMD, STOP_CMD, PONDERHIT_CMD, QUIT_CMD];
+
+const STARTPOS_PARAM: &str    = "startpos";
+const FEN_PARAM: &str         = "fen";
+const SEARCHMOVES_PARAM: &str = "searchmoves";
+const WTIME_PARAM: &str       = "wtime";
+const BTIME_PARAM: &str       = "btime";
+const WINC_PARAM: &str        = "winc";
+const BINC_PARAM: &str        = "binc";
+const MOVESTOGO_PARAM: &str   = "movestogo";
+const DEPTH_PARAM: &str       = "depth";
+const NODES_PARAM: &str       = "nodes";
+const MATE_PARAM: &str        = "mate";
+const MOVETIME_PARAM: &str    = "movetime";
+const INFINITE_PARAM: &str    = "infinite";
+const PERFT_PARAM: &str       = "perft";
+
+const GO_PARAMS: [&str; 13] = [SEARCHMOVES_PARAM, PONDER_PARAM, WTIME_PARAM, BTIME_PARAM, WINC_PARAM, BINC_PARAM, MOVESTOGO_PARAM,
+    DEPTH_PARAM, NODES_PARAM, MATE_PARAM, MOVETIME_PARAM, INFINITE_PARAM, PERFT_PARAM];
+
+const SELDEPTH_PARAM: &str       = "seldepth";
+const TIME_PARAM: &str           = "time";
+const PV_PARAM: &str             = "pv";
+const MULTIPV_PARAM: &str        = "multipv";
+const SCORE_PARAM: &str          = "score";
+const CP_PARAM: &str             = "cp";
+const LOWERBOUND_PARAM: &str     = "lowerbound";
+const UPPERBOUND_PARAM: &str     = "upperbound";
+const WDL_PARAM: &str            = "wdl";
+const CURRMOVE_PARAM: &str       = "currmove";
+const CURRMOVENUMBER_PARAM: &str = "currmovenumber";
+const HASHFULL_PARAM: &str       = "hashfull";
+const NPS_PARAM: &str            = "nps";
+const TBHITS_PARAM: &str         = "tbhits";
+const CPULOAD_PARAM: &str        = "cpuload";
+const SBHITS_PARAM: &str         = "sbhits";
+const REFUTATION_PARAM: &str     = "refutation";
+const CURRLINE_PARAM: &str       = "currline";
+
+const INFO_PARAMS: [&str; 18] = [DEPTH_PARAM, SELDEPTH_PARAM, TIME_PARAM, NODES_PARAM, PV_PARAM, MULTIPV_PARAM, SCORE_PARAM, WDL_PARAM,
+    CURRMOVE_PARAM, CURRMOVENUMBER_PARAM, HASHFULL_PARAM, NPS_PARAM, TBHITS_PARAM, SBHITS_PARAM, CPULOAD_PARAM, STRING_PARAM, REFUTATION_PARAM, CURRLINE_PARAM];
+
+const TYPE_PARAM: &str    = "type";
+const DEFAULT_PARAM: &str = "default";
+const MIN_PARAM: &str     = "min";
+const MAX_PARAM: &str     = "max";
+const VAR_PARAM: &str     = "var";
+
+const CHECK_TYPE: &str  = "check";
+const SPIN_TYPE: &str   = "spin";
+const COMBO_TYPE: &str  = "combo";
+const BUTTON_TYPE: &str = "button";
+const STRING_TYPE: &str = "string";
+
+// A line sent by the GUI.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Uci,
+    Debug(bool),
+    IsReady,
+    SetOption(String, Option<String>),
+    Register(String),
+    UciNewGame,
+    Position(Position),
+    Go(Go),
+    Stop,
+    PonderHit,
+    Quit,
+    Unknown(String),
+}
+
+// A position command; no FEN means the start position.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Position {
+    pub fen: Option<String>,
+    pub moves: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Go {
+    pub searchmoves: Vec<String>,
+    pub ponder: bool,
+    pub wtime: Option<i64>,
+    pub btime: Option<i64>,
+    pub winc: Option<i64>,
+    pub binc: Option<i64>,
+    pub movestogo: Option<u32>,
+    pub depth: Option<u32>,
+    pub nodes: Option<u64>,
+    pub mate: Option<u32>,
+    pub movetime: Option<i64>,
+    pub infinite: bool,
+}
+
+// A line printed by the engine. Anything that is not a UCI response, such
+// as a start-up banner, is Unknown.
+#[derive(Debug, PartialEq)]
+pub enum Response {
+    IdName(String),
+    IdAuthor(String),
+    UciOk,
+    ReadyOk,
+    BestMove(String, Option<String>),
+    CopyProtection(String),
+    Registration(String),
+    Option(OptionDecl),
+    Info(Info),
+    InfoString(String),
+    Unknown(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Score {
+    Cp(i64),
+    Mate(i64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bound {
+    Lower,
+    Upper,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Info {
+    pub depth: Option<u32>,
+    pub seldepth: Option<u32>,
+    pub multipv: Option<usize>,
+    pub score: Option<Score>,
+    pub bound: Option<Bound>,
+    pub wdl: Option<(u32, u32, u32)>,
+    pub nodes: Option<u64>,
+    pub nps: Option<u64>,
+    pub hashfull: Option<u32>,
+    pub tbhits: Option<u64>,
+    pub time: Option<u64>,
+    pub currmove: Option<String>,
+    pub currmovenumber: Option<u32>,
+    pub cpuload: Option<u32>,
+    pub pv: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OptionType {
+    Check(bool),
+    Spin { default: i64, min: i64, max: i64 },
+    Combo { default: String, vars: Vec<String> },
+    Button,
+    String(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OptionDecl {
+    pub name: String,
+    pub kind: OptionType,
+}
+
+// What is left of the line after its first n words, with inner spacing kept.
+pub fn skip_words(line: &str, n: usize) -> &str {
+    let mut rest = line.trim_start();
+    for _ in 0..n {
+        rest = rest.trim_start_matches(|c: char| !c.is_ascii_whitespace()).trim_start();
+    }
+    rest.trim_end()
+}
+
+// The value following a keyword, unless the keyword has none and another
+// keyword follows instead.
+fn next_value<'a, I>(words: &mut std::iter::Peekable<I>, keywords: &[&str]) -> Option<&'a str> where
+    I: Iterator<Item = &'a str>
+{
+    match words.peek() {
+        Some(x) if !keywords.contains(x) => words.next(),
+        _ => None
+    }
+}
+
+fn next_number<'a, I, T>(words: &mut std::iter::Peekable<I>, keywords: &[&str]) -> Option<T> where
+    I: Iterator<Item = &'a str>,
+    T: std::str::FromStr
+{
+    next_value(words, keywords).and_then(|x| x.parse().ok())
+}
+
+// Unknown words in front of a command are skipped, as the UCI spec asks.
+pub fn parse_command(line: &str) -> Command {
+    let words: Vec<&str> = line.split_ascii_whitespace().collect();
+    let start = match words.iter().position(|x| COMMANDS.contains(x)) {
+        Some(i) => i,
+        None => return Command::Unknown(line.trim().to_string())
+    };
+    let args = &words[start + 1..];
+    let command = match words[start] {
+        UCI_CMD => Some(Command::Uci),
+        DEBUG_CMD => match args.first() {
+            Some(&ON_VALUE) => Some(Command::Debug(true)),
+            Some(&OFF_VALUE) => Some(Command::Debug(false)),
+            _ => None
+        },
+        ISREADY_CMD => Some(Command::IsReady),
+        SETOPTION_CMD => parse_setoption(args),
+        REGISTER_CMD => Some(Command::Register(args.join(" "))),
+        UCINEWGAME_CMD => Some(Command::UciNewGame),
+        POSITION_CMD => parse_position(args).map(Command::Position),
+        GO_CMD => Some(Command::Go(parse_go(args))),
+        STOP_CMD => Some(Command::Stop),
+        PONDERHIT_CMD => Some(Command::PonderHit),
+        _ => Some(Command::Quit)
+    };
+    command.unwrap_or_else(|| Command::Unknown(line.trim().to_string()))
+}
+
+fn parse_setoption(args: &[&str]) -> Option<Command> {
+    if args.first() != Some(&NAME_PARAM) {
+        return None
+    }
+    let name = args[1..].iter().take_while(|x| **x != VALUE_PARAM).cloned().collect::<Vec<&str>>().join(" ");
+    if name.is_empty() {
+        return None
+    }
+    let value = args.iter().position(|x| *x == VALUE_PARAM).map(|i| args[i + 1..].join(" "));
+    Some(Command::SetOption(name, value))
+}
+
+fn parse_position(args: &[&str]) -> Option<Position> {
+    let (fen, rest) = match args.first() {
+        Some(&STARTPOS_PARAM) => (None, &args[1..]),
+        Some(&FEN_PARAM) => {
+            let end = args.iter().position(|x| *x == MOVES_PARAM).unwrap_or(args.len());
+            if end == 1 {
+                return None
+            }
+            (Some(args[1..end].join(" ")), &args[end..])
+        }
+        _ => return None
+    };
+    let moves = match rest.first() {
+        None => vec![],
+        Some(&MOVES_PARAM) => rest[1..].iter().map(|x| x.to_string()).collect(),
+        Some(_) => return None
+    };
+    Some(Position { fen, moves })
+}
+
+pub fn parse_go(args: &[&str]) -> Go {
+    let mut go = Go::default();
+    let mut words = args.iter().cloned().peekable();
+    while let Some(word) = words.next() {
+        match word {
+            SEARCHMOVES_PARAM => while let Some(x) = next_value(&mut words, &GO_PARAMS) {
+                go.searchmoves.push(x.to_string());
+            },
+            PONDER_PARAM => go.ponder = true,
+            WTIME_PARAM => go.wtime = next_number(&mut words, &GO_PARAMS),
+            BTIME_PARAM => go.btime = next_number(&mut words, &GO_PARAMS),
+            WINC_PARAM => go.winc = next_number(&mut words, &GO_PARAMS),
+            BINC_PARAM => go.binc = next_number(&mut words, &GO_PARAMS),
+            MOVESTOGO_PARAM => go.movestogo = next_number(&mut words, &GO_PARAMS),
+            DEPTH_PARAM => go.depth = next_number(&mut words, &GO_PARAMS),
+            NODES_PARAM => go.nodes = next_number(&mut words, &GO_PARAMS),
+            MATE_PARAM => go.mate = next_number(&mut words, &GO_PARAMS),
+            MOVETIME_PARAM => go.movetime = next_number(&mut words, &GO_PARAMS),
+            INFINITE_PARAM => go.infinite = true,
+            _ => ()
+        }
+    }
+    go
+}
+
+pub fn parse_response(line: &str) -> Response {
+    let mut words = line.split_ascii_whitespace();
+    let response = match (words.next(), words.next()) {
+        (Some(ID_RESP), Some(NAME_PARAM)) => Some(Response::IdName(skip_words(line, 2).to_string())),
+        (Some(ID_RESP), Some(AUTHOR_PARAM)) => Some(Response::IdAuthor(skip_words(line, 2).to_string())),
+        (Some(UCIOK_RESP), None) => Some(Response::UciOk),
+        (Some(READYOK_RESP), None) => Some(Response::ReadyOk),
+        (Some(BESTMOVE_RESP), Some(best)) => match (words.next(), words.next()) {
+            (Some(PONDER_PARAM), Some(ponder)) => Some(Response::BestMove(best.to_string(), Some(ponder.to_string()))),
+            _ => Some(Response::BestMove(best.to_string(), None))
+        },
+        (Some(COPYPROTECTION_RESP), Some(_)) => Some(Response::CopyProtection(skip_words(line, 1).to_string())),
+        (Some(REGISTRATION_RESP), Some(_)) => Some(Response::Registration(skip_words(line, 1).to_string())),
+        (Some(OPTION_RESP), Some(_)) => parse_option(line).map(Response::Option),
+        (Some(INFO_RESP), Some(STRING_PARAM)) => Some(Response::InfoString(skip_words(line, 2).to_string())),
+        (Some(INFO_RESP), Some(_)) => Some(Response::Info(parse_info(line))),
+        _ => None
+    };
+    response.unwrap_or_else(|| Response::Unknown(line.to_string()))
+}
+
+// Unknown fields are skipped; a string field ends the line.
+fn parse_info(line: &str) -> Info {
+    let mut info = Info::default();
+    let mut words = line.split_ascii_whitespace().skip(1).peekable();
+    while let Some(word) = words.next() {
+        match word {
+            DEPTH_PARAM => info.depth = next_number(&mut words, &INFO_PARAMS),
+            SELDEPTH_PARAM => info.seldepth = next_number(&mut words, &INFO_PARAMS),
+            TIME_PARAM => info.time = next_number(&mut words, &INFO_PARAMS),
+            NODES_PARAM => info.nodes = next_number(&mut words, &INFO_PARAMS),
+            MULTIPV_PARAM => info.multipv = next_number(&mut words, &INFO_PARAMS),
+            SCORE_PARAM => {
+                info.score = match (words.next(), words.next().and_then(|x| x.parse().ok())) {
+                    (Some(CP_PARAM), Some(x)) => Some(Score::Cp(x)),
+                    (Some(MATE_PARAM), Some(x)) => Some(Score::Mate(x)),
+                    _ => None
+                };
+                info.bound = match words.peek() {
+                    Some(&LOWERBOUND_PARAM) => Some(Bound::Lower),
+                    Some(&UPPERBOUND_PARAM) => Some(Bound::Upper),
+                    _ => None
+                };
+                if info.bound.is_some() {
+                    words.next();
+                }
+            }
+            WDL_PARAM => {
+                info.wdl = match (next_number(&mut words, &INFO_PARAMS), next_number(&mut words, &INFO_PARAMS), next_number(&mut words, &INFO_PARAMS)) {
+                    (Some(w), Some(d), Some(l)) => Some((w, d, l)),
+                    _ => None
+                }
+            }
+            CURRMOVE_PARAM => info.currmove = next_value(&mut words, &INFO_PARAMS).map(|x| x.to_string()),
+            CURRMOVENUMBER_PARAM => info.currmovenumber = next_number(&mut words, &INFO_PARAMS),
+            HASHFULL_PARAM => info.hashfull = next_number(&mut words, &INFO_PARAMS),
+            NPS_PARAM => info.nps = next_number(&mut words, &INFO_PARAMS),
+            TBHITS_PARAM => info.tbhits = next_number(&mut words, &INFO_PARAMS),
+            CPULOAD_PARAM => info.cpuload = next_number(&mut words, &INFO_PARAMS),
+            PV_PARAM => while let Some(x) = next_value(&mut words, &INFO_PARAMS) {
+                info.pv.push(x.to_string());
+            },
+            STRING_PARAM => break,
+            _ => ()
+        }
+    }
+    info
+}
+
+// Option names and string values may contain spaces, so every field runs
+// until the next keyword.
+fn parse_option(line: &str) -> Option<OptionDecl> {
+    let keywords = [NAME_PARAM, TYPE_PARAM, DEFAULT_PARAM, MIN_PARAM, MAX_PARAM, VAR_PARAM];
+    let mut fields: Vec<(&str, Vec<&str>)> = vec![];
+    for word in line.split_ascii_whitespace().skip(1) {
+        match fields.last_mut() {
+            // A name may itself contain keywords other than type.
+            Some((NAME_PARAM, value)) if word != TYPE_PARAM => value.push(word),
+            Some((_, value)) if !keywords.contains(&word) => value.push(word),
+            _ if keywords.contains(&word) => fields.push((word, vec![])),
+            _ => return None
+        }
+    }
+    let field = |key: &str| fields.iter().find(|(x, _)| *x == key).map(|(_, value)| value.join(" "));
+    let number = |key: &str| field(key).and_then(|x| x.parse().ok()).unwrap_or(0);
+
+    let name = field(NAME_PARAM).filter(|x| !x.is_empty())?;
+    let kind = match field(TYPE_PARAM)?.as_str() {
+        CHECK_TYPE => OptionType::Check(field(DEFAULT_PARAM).as_deref() == Some(TRUE_VALUE)),
+        SPIN_TYPE => OptionType::Spin { default: number(DEFAULT_PARAM), min: number(MIN_PARAM), max: number(MAX_PARAM) },
+        COMBO_TYPE => OptionType::Combo {
+            default: field(DEFAULT_PARAM).unwrap_or_default(),
+            vars: fields.iter().filter(|(x, _)| *x == VAR_PARAM).map(|(_, value)| value.join(" ")).collect(),
+        },
+        BUTTON_TYPE => OptionType::Button,
+        STRING_TYPE => OptionType::String(field(DEFAULT_PARAM).unwrap_or_default()),
+        _ => return None
+    };
+    Some(OptionDecl { name, kind })
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Uci => write!(f, "{}", UCI_CMD),
+            Command::Debug(on) => write!(f, "{} {}", DEBUG_CMD, if *on { ON_VALUE } else { OFF_VALUE }),
+            Command::IsReady => write!(f, "{}", ISREADY_CMD),
+            Command::SetOption(name, Some(value)) => write!(f, "{} {} {} {} {}", SETOPTION_CMD, NAME_PARAM, name, VALUE_PARAM, value),
+            Command::SetOption(name, None) => write!(f, "{} {} {}", SETOPTION_CMD, NAME_PARAM, name),
+            Command::Register(args) => write!(f, "{} {}", REGISTER_CMD, args),
+            Command::UciNewGame => write!(f, "{}", UCINEWGAME_CMD),
+            Command::Position(position) => write!(f, "{}", position),
+            Command::Go(go) => write!(f, "{}", go),
+            Command::Stop => write!(f, "{}", STOP_CMD),
+            Command::PonderHit => write!(f, "{}", PONDERHIT_CMD),
+            Command::Quit => write!(f, "{}", QUIT_CMD),
+            Command::Unknown(line) => write!(f, "{}", line),
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.fen {
+            Some(fen) => write!(f, "{} {} {}", POSITION_CMD, FEN_PARAM, fen)?,
+            None => write!(f, "{} {}", POSITION_CMD, STARTPOS_PARAM)?
+        }
+        if !self.moves.is_empty() {
+            write!(f, " {} {}", MOVES_PARAM, self.moves.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+// Search moves go last since they run until the next keyword.
+impl fmt::Display for Go {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", GO_CMD)?;
+        if self.ponder {
+            write!(f, " {}", PONDER_PARAM)?;
+        }
+        let numbers = [
+            (WTIME_PARAM, self.wtime),
+            (BTIME_PARAM, self.btime),
+            (WINC_PARAM, self.winc),
+            (BINC_PARAM, self.binc),
+            (MOVESTOGO_PARAM, self.movestogo.map(i64::from)),
+            (DEPTH_PARAM, self.depth.map(i64::from)),
+            (NODES_PARAM, self.nodes.map(|x| x as i64)),
+            (MATE_PARAM, self.mate.map(i64::from)),
+            (MOVETIME_PARAM, self.movetime),
+        ];
+        for (name, value) in numbers.iter() {
+            if let Some(value) = value {
+                write!(f, " {} {}", name, value)?;
+            }
+        }
+        if self.infinite {
+            write!(f, " {}", INFINITE_PARAM)?;
+        }
+        if !self.searchmoves.is_empty() {
+            write!(f, " {} {}", SEARCHMOVES_PARAM, self.searchmoves.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Score {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Score::Cp(x) => write!(f, "{} {}", CP_PARAM, x),
+            Score::Mate(x) => write!(f, "{} {}", MATE_PARAM, x),
+        }
+    }
+}
+
+impl fmt::Display for Info {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", INFO_RESP)?;
+        let numbers = [(DEPTH_PARAM, self.depth.map(u64::from)), (SELDEPTH_PARAM, self.seldepth.map(u64::from)), (MULTIPV_PARAM, self.multipv.map(|x| x as u64))];
+        for (name, value) in numbers.iter() {
+            if let Some(value) = value {
+                write!(f, " {} {}", name, value)?;
+            }
+        }
+        if let Some(score) = self.score {
+            write!(f, " {} {}", SCORE_PARAM, score)?;
+        }
+        match self.bound {
+            Some(Bound::Lower) => write!(f, " {}", LOWERBOUND_PARAM)?,
+            Some(Bound::Upper) => write!(f, " {}", UPPERBOUND_PARAM)?,
+            None => ()
+        }
+        if let Some((w, d, l)) = self.wdl {
+            write!(f, " {} {} {} {}", WDL_PARAM, w, d, l)?;
+        }
+        let numbers = [(NODES_PARAM, self.nodes), (NPS_PARAM, self.nps), (HASHFULL_PARAM, self.hashfull.map(u64::from)), (TBHITS_PARAM, self.tbhits), (TIME_PARAM, self.time)];
+        for (name, value) in numbers.iter() {
+            if let Some(value) = value {
+                write!(f, " {} {}", name, value)?;
+            }
+        }
+        if let Some(currmove) = &self.currmove {
+            write!(f, " {} {}", CURRMOVE_PARAM, currmove)?;
+        }
+        if let Some(currmovenumber) = self.currmovenumber {
+            write!(f, " {} {}", CURRMOVENUMBER_PARAM, currmovenumber)?;
+        }
+        if let Some(cpuload) = self.cpuload {
+            write!(f, " {} {}", CPULOAD_PARAM, cpuload)?;
+        }
+        if !self.pv.is_empty() {
+            write!(f, " {} {}", PV_PARAM, self.pv.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for OptionDecl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {} {} ", OPTION_RESP, NAME_PARAM, self.name, TYPE_PARAM)?;
+        match &self.kind {
+            OptionType::Check(default) => write!(f, "{} {} {}", CHECK_TYPE, DEFAULT_PARAM, default),
+            OptionType::Spin { default, min, max } => write!(f, "{} {} {} {} {} {} {}", SPIN_TYPE, DEFAULT_PARAM, default, MIN_PARAM, min, MAX_PARAM, max),
+            OptionType::Combo { default, vars } => {
+                write!(f, "{} {} {}", COMBO_TYPE, DEFAULT_PARAM, default)?;
+                for var in vars {
+                    write!(f, " {} {}", VAR_PARAM, var)?;
+                }
+                Ok(())
+            }
+            OptionType::Button => write!(f, "{}", BUTTON_TYPE),
+            OptionType::String(default) => write!(f, "{} {} {}", STRING_TYPE, DEFAULT_PARAM, default),
+        }
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Response::IdName(name) => write!(f, "{} {} {}", ID_RESP, NAME_PARAM, name),
+            Response::IdAuthor(author) => write!(f, "{} {} {}", ID_RESP, AUTHOR_PARAM, author),
+            Response::UciOk => write!(f, "{}", UCIOK_RESP),
+            Response::ReadyOk => write!(f, "{}", READYOK_RESP),
+            Response::BestMove(best, Some(ponder)) => write!(f, "{} {} {} {}", BESTMOVE_RESP, best, PONDER_PARAM, ponder),
+            Response::BestMove(best, None) => write!(f, "{} {}", BESTMOVE_RESP, best),
+            Response::CopyProtection(status) => write!(f, "{} {}", COPYPROTECTION_RESP, status),
+            Response::Registration(status) => write!(f, "{} {}", REGISTRATION_RESP, status),
+            Response::Option(option) => write!(f, "{}", option),
+            Response::Info(info) => write!(f, "{}", info),
+            Response::InfoString(text) => write!(f, "{} {} {}", INFO_RESP, STRING_PARAM, text),
+            Response::Unknown(line) => write!(f, "{}", line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip_command(line: &str) {
+        assert_eq!(parse_command(line).to_string(), line);
+    }
+
+    fn round_trip_response(line: &str) {
+        assert_eq!(parse_response(line).to_string(), line);
+    }
+
+    #[test]
+    fn parses_simple_commands() {
+        assert_eq!(parse_command("uci"), Command::Uci);
+        assert_eq!(parse_command("isready"), Command::IsReady);
+        assert_eq!(parse_command("ucinewgame"), Command::UciNewGame);
+        assert_eq!(parse_command("stop"), Command::Stop);
+        assert_eq!(parse_command("ponderhit"), Command::PonderHit);
+        assert_eq!(parse_command("quit"), Command::Quit);
+        assert_eq!(parse_command("debug off"), Command::Debug(false));
+        assert_eq!(parse_command("  isready  "), Command::IsReady);
+    }
+
+    #[test]
+    fn skips_unknown_words_before_a_command() {
+        assert_eq!(parse_command("joho debug on"), Command::Debug(true));
+        assert_eq!(parse_command("hello there"), Command::Unknown("hello there".to_string()));
+        assert_eq!(parse_command("debug"), Command::Unknown("debug".to_string()));
+    }
+
+    #[test]
+    fn parses_setoption() {
+        assert_eq!(parse_command("setoption name Selectivity value 3"), Command::SetOption("Selectivity".to_string(), Some("3".to_string())));
+        assert_eq!(parse_command("setoption name Clear Hash"), Command::SetOption("Clear Hash".to_string(), None));
+        assert_eq!(parse_command("setoption name NalimovPath value c:\\chess\\tb\\4;c:\\chess\\tb\\5"),
+            Command::SetOption("NalimovPath".to_string(), Some("c:\\chess\\tb\\4;c:\\chess\\tb\\5".to_string())));
+        assert_eq!(parse_command("setoption name LichessDB_Player value Some Player"), Command::SetOption("LichessDB_Player".to_string(), Some("Some Player".to_string())));
+        assert_eq!(parse_command("setoption value 3"), Command::Unknown("setoption value 3".to_string()));
+        round_trip_command("setoption name Selectivity value 3");
+        round_trip_command("setoption name Clear Hash");
+    }
+
+    #[test]
+    fn parses_register() {
+        assert_eq!(parse_command("register later"), Command::Register("later".to_string()));
+        round_trip_command("register name Stefan MK code 4359874324");
+    }
+
+    #[test]
+    fn parses_position() {
+        assert_eq!(parse_command("position startpos"), Command::Position(Position { fen: None, moves: vec![] }));
+        assert_eq!(parse_command("position startpos moves e2e4 e7e5"),
+            Command::Position(Position { fen: None, moves: vec!["e2e4".to_string(), "e7e5".to_string()] }));
+        let fen = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1";
+        assert_eq!(parse_command(format!("position fen {} moves e7e5", fen).as_str()),
+            Command::Position(Position { fen: Some(fen.to_string()), moves: vec!["e7e5".to_string()] }));
+        assert_eq!(parse_command("position fen"), Command::Unknown("position fen".to_string()));
+        assert_eq!(parse_command("position startpos e2e4"), Command::Unknown("position startpos e2e4".to_string()));
+        round_trip_command("position startpos moves e2e4 e7e5");
+        round_trip_command(format!("position fen {}", fen).as_str());
+    }
+
+    #[test]
+    fn parses_go() {
+        let go = parse_go(&["wtime", "300000", "btime", "298000", "winc", "2000", "binc", "2000", "movestogo", "40"]);
+        assert_eq!(go, Go { wtime: Some(300000), btime: Some(298000), winc: Some(2000), binc: Some(2000), movestogo: Some(40), ..Go::default() });
+        assert_eq!(parse_command("go infinite searchmoves e2e4 d2d4"),
+            Command::Go(Go { infinite: true, searchmoves: vec!["e2e4".to_string(), "d2d4".to_string()], ..Go::default() }));
+        assert_eq!(parse_command("go searchmoves e2e4 ponder"),
+            Command::Go(Go { ponder: true, searchmoves: vec!["e2e4".to_string()], ..Go::default() }));
+        round_trip_command("go ponder wtime 1000 btime 1000 winc 0 binc 0");
+        round_trip_command("go depth 12 searchmoves g1f3");
+        round_trip_command("go movetime 500");
+    }
+
+    #[test]
+    fn ignores_bad_go_numbers() {
+        assert_eq!(parse_go(&["wtime", "abc", "movetime", "100"]), Go { movetime: Some(100), ..Go::default() });
+        assert_eq!(parse_go(&["wtime", "movetime", "100"]), Go { movetime: Some(100), ..Go::default() });
+        assert_eq!(parse_go(&["depth"]), Go::default());
+        assert_eq!(parse_go(&["wtime", "-150"]).wtime, Some(-150));
+    }
+
+    #[test]
+    fn parses_id_and_status_responses() {
+        assert_eq!(parse_response("id name Shredder X.Y"), Response::IdName("Shredder X.Y".to_string()));
+        assert_eq!(parse_response("id author Stefan MK"), Response::IdAuthor("Stefan MK".to_string()));
+        assert_eq!(parse_response("uciok"), Response::UciOk);
+        assert_eq!(parse_response("readyok"), Response::ReadyOk);
+        assert_eq!(parse_response("copyprotection checking"), Response::CopyProtection("checking".to_string()));
+        assert_eq!(parse_response("registration error"), Response::Registration("error".to_string()));
+        assert_eq!(parse_response("Stockfish 16 by the Stockfish developers"), Response::Unknown("Stockfish 16 by the Stockfish developers".to_string()));
+    }
+
+    #[test]
+    fn parses_bestmove() {
+        assert_eq!(parse_response("bestmove g1f3 ponder d8f6"), Response::BestMove("g1f3".to_string(), Some("d8f6".to_string())));
+        assert_eq!(parse_response("bestmove (none)"), Response::BestMove("(none)".to_string(), None));
+        round_trip_response("bestmove g1f3 ponder d8f6");
+    }
+
+    #[test]
+    fn parses_info() {
+        let info = match parse_response("info depth 2 score cp 214 time 1242 nodes 2124 nps 34928 pv e2e4 e7e5 g1f3") {
+            Response::Info(info) => info,
+            x => panic!("{:?}", x)
+        };
+        assert_eq!(info.depth, Some(2));
+        assert_eq!(info.score, Some(Score::Cp(214)));
+        assert_eq!(info.time, Some(1242));
+        assert_eq!(info.nodes, Some(2124));
+        assert_eq!(info.nps, Some(34928));
+        assert_eq!(info.pv, vec!["e2e4", "e7e5", "g1f3"]);
+
+        let info = match parse_response("info depth 5 seldepth 8 multipv 2 score mate -3 upperbound nodes 10 pv a2a3") {
+            Response::Info(info) => info,
+            x => panic!("{:?}", x)
+        };
+        assert_eq!(info.multipv, Some(2));
+        assert_eq!(info.score, Some(Score::Mate(-3)));
+        assert_eq!(info.bound, Some(Bound::Upper));
+
+        round_trip_response("info depth 12 seldepth 18 multipv 1 score cp -12 lowerbound wdl 100 800 100 nodes 123456 nps 100000 hashfull 12 tbhits 0 time 1234 pv e2e4 e7e5");
+        round_trip_response("info currmove e2e4 currmovenumber 1");
+    }
+
+    #[test]
+    fn parses_info_string() {
+        assert_eq!(parse_response("info string NNUE evaluation  enabled"), Response::InfoString("NNUE evaluation  enabled".to_string()));
+        round_trip_response("info string hello world");
+    }
+
+    #[test]
+    fn parses_options() {
+        assert_eq!(parse_response("option name Nullmove type check default true"),
+            Response::Option(OptionDecl { name: "Nullmove".to_string(), kind: OptionType::Check(true) }));
+        assert_eq!(parse_response("option name Selectivity type spin default 2 min 0 max 4"),
+            Response::Option(OptionDecl { name: "Selectivity".to_string(), kind: OptionType::Spin { default: 2, min: 0, max: 4 } }));
+        assert_eq!(parse_response("option name Style type combo default Normal var Solid var Normal var Risky"),
+            Response::Option(OptionDecl { name: "Style".to_string(), kind: OptionType::Combo {
+                default: "Normal".to_string(),
+                vars: vec!["Solid".to_string(), "Normal".to_string(), "Risky".to_string()],
+            } }));
+        assert_eq!(parse_response("option name Clear Hash type button"),
+            Response::Option(OptionDecl { name: "Clear Hash".to_string(), kind: OptionType::Button }));
+        assert_eq!(parse_response("option name NalimovPath type string default c:\\"),
+            Response::Option(OptionDecl { name: "NalimovPath".to_string(), kind: OptionType::String("c:\\".to_string()) }));
+        assert_eq!(parse_response("option name Broken"), Response::Unknown("option name Broken".to_string()));
+        round_trip_response("option name Style type combo default Normal var Solid var Normal var Risky");
+        round_trip_response("option name UCI_Elo type spin default 1320 min 1320 max 3190");
+        round_trip_response("option name Debug Log File type string default <empty>");
+    }
+}